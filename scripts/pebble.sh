@@ -0,0 +1,53 @@
+#!/bin/bash
+# End-to-end check of the ACME client against Pebble (https://github.com/letsencrypt/pebble).
+# Run from the repository root with a release build of the server.
+
+set -e
+
+SERVER=${SERVER:-./server/target/release/server}
+WORKDIR=$(mktemp -d)
+DOMAIN=test.example
+
+cleanup() {
+    kill $SERVER_PID 2> /dev/null || true
+    docker rm -f pebble pebble-dns > /dev/null 2>&1 || true
+    rm -rf $WORKDIR
+}
+trap cleanup EXIT
+
+echo 'Launching Pebble...'
+# Every name resolves to localhost, and Pebble validates HTTP-01 challenges on port 5002.
+docker run --name pebble-dns --network host -d \
+    ghcr.io/letsencrypt/pebble-challtestsrv -defaultIPv6 "" -defaultIPv4 127.0.0.1
+docker run --name pebble --network host -e PEBBLE_VA_NOSLEEP=1 -d \
+    ghcr.io/letsencrypt/pebble -dnsserver 127.0.0.1:8053
+sleep 2
+docker cp pebble:/test/certs/pebble.minica.pem $WORKDIR/minica.pem
+
+echo 'Launching server...'
+mkdir -p $WORKDIR/source $WORKDIR/private
+echo 'hello' > $WORKDIR/source/index.html
+$SERVER \
+    --address 127.0.0.1:5002 \
+    --source $WORKDIR/source \
+    --private-source $WORKDIR/private \
+    --config $WORKDIR/config.json \
+    --image-cache $WORKDIR/cache \
+    --redirects $WORKDIR/redirects.json \
+    --tls-address 127.0.0.1:5001 \
+    --acme-domains $DOMAIN \
+    --acme-directory https://localhost:14000/dir \
+    --acme-dir $WORKDIR/acme \
+    --acme-ca-cert $WORKDIR/minica.pem &
+SERVER_PID=$!
+
+for _ in $(seq 30); do
+    [ -f $WORKDIR/acme/certificate.pem ] && break
+    sleep 1
+done
+
+# Certificates are issued by a root which is generated when Pebble starts.
+curl -sk https://localhost:15000/roots/0 > $WORKDIR/root.pem
+sleep 1
+curl -sf --cacert $WORKDIR/root.pem --resolve $DOMAIN:5001:127.0.0.1 https://$DOMAIN:5001/ > /dev/null
+echo 'Obtained and served the certificate from Pebble.'
@@ -16,6 +16,7 @@ docker run --name static \
     -e PRIVATE_SOURCE=/private \
     -e CONFIG=/config/static_server_config.json \
     -e LOG_LEVEL=info \
+    -e TRUSTED_PROXIES=172.16.0.0/12 \
     -d wafflespeanut/static-server
 
 echo 'Launching ace game!'
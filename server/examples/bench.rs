@@ -0,0 +1,149 @@
+//! Measures the throughput of the server for a single URL.
+//!
+//! ```sh
+//! cargo run --release --example bench -- http://localhost:8000/ --connections 32 --duration 10
+//! cargo run --release --example bench -- http://localhost:8000/ --http2 --streams 8
+//! ```
+
+use async_std::net::TcpStream;
+use async_std::task;
+use clap::Parser;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::client::conn::{http1, http2};
+use hyper::{Request, Uri};
+use smol_hyper::rt::FuturesIo;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+struct Args {
+    /// URL to request (plain HTTP only)
+    url: Uri,
+    /// Number of connections
+    #[arg(long, default_value_t = 32)]
+    connections: usize,
+    /// Duration of the benchmark in seconds
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+    /// Use HTTP/2 (with prior knowledge) instead of HTTP/1.1
+    #[arg(long)]
+    http2: bool,
+    /// Number of concurrent streams for each HTTP/2 connection
+    #[arg(long, default_value_t = 8)]
+    streams: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Executor;
+
+impl<F> hyper::rt::Executor<F> for Executor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        task::spawn(fut);
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+}
+
+fn request(uri: &Uri) -> Request<Empty<Bytes>> {
+    Request::get(uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"))
+        .header("Host", uri.authority().map(|a| a.as_str()).unwrap_or(""))
+        .body(Empty::new())
+        .expect("building request")
+}
+
+/// Keep sending requests until the deadline (and record the results).
+async fn run<F, Fut>(deadline: Instant, stats: Arc<Stats>, mut send: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u64, hyper::Error>>,
+{
+    while Instant::now() < deadline {
+        match send().await {
+            Ok(bytes) => {
+                stats.requests.fetch_add(1, Ordering::Relaxed);
+                stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+            Err(_) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+async fn connection(args: Arc<Args>, deadline: Instant, stats: Arc<Stats>) {
+    let host = args.url.host().unwrap_or("localhost");
+    let port = args.url.port_u16().unwrap_or(80);
+    let stream = match TcpStream::connect((host, port)).await {
+        Ok(s) => FuturesIo::new(s),
+        Err(e) => return eprintln!("Cannot connect: {}", e),
+    };
+
+    if !args.http2 {
+        let (mut sender, conn) = http1::handshake(stream).await.expect("handshake");
+        task::spawn(conn);
+        let uri = args.url.clone();
+        return run(deadline, stats, || {
+            let resp = sender.send_request(request(&uri));
+            async move {
+                let body = resp.await?.into_body().collect().await?;
+                Ok(body.to_bytes().len() as u64)
+            }
+        })
+        .await;
+    }
+
+    let (sender, conn) = http2::handshake(Executor, stream).await.expect("handshake");
+    task::spawn(conn);
+    let streams = (0..args.streams).map(|_| {
+        let (mut sender, stats, uri) = (sender.clone(), stats.clone(), args.url.clone());
+        task::spawn(async move {
+            run(deadline, stats, || {
+                let resp = sender.send_request(request(&uri));
+                async move {
+                    let body = resp.await?.into_body().collect().await?;
+                    Ok(body.to_bytes().len() as u64)
+                }
+            })
+            .await
+        })
+    });
+
+    futures::future::join_all(streams).await;
+}
+
+#[async_std::main]
+async fn main() {
+    let args = Arc::new(Args::parse());
+    let stats = Arc::new(Stats::default());
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration);
+    let connections = (0..args.connections)
+        .map(|_| task::spawn(connection(args.clone(), deadline, stats.clone())));
+    futures::future::join_all(connections).await;
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let requests = stats.requests.load(Ordering::Relaxed);
+    let bytes = stats.bytes.load(Ordering::Relaxed);
+    println!(
+        "{} requests in {:.1}s ({} errors): {:.0} req/s, {:.1} MiB/s",
+        requests,
+        elapsed,
+        stats.errors.load(Ordering::Relaxed),
+        requests as f64 / elapsed,
+        bytes as f64 / elapsed / (1 << 20) as f64
+    );
+}
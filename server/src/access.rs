@@ -0,0 +1,245 @@
+use crate::proxy;
+use crate::settings::{AccessLogFormat, AccessLogSettings};
+use crate::util;
+use chrono::offset::Utc;
+use chrono::{DateTime, SecondsFormat};
+use crossbeam_channel::{self as mpmc, Sender};
+use tide::{Middleware, Next, Request};
+use uuid::Uuid;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+
+/// Replacement for the private tokens in the logged paths.
+const REDACTED: &str = "[redacted]";
+
+/// A single request (and how it was responded to).
+struct Entry {
+    time: DateTime<Utc>,
+    client_ip: String,
+    method: String,
+    path: String,
+    protocol: String,
+    status: u16,
+    bytes: u64,
+    duration_ms: f64,
+    referrer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn format(&self, format: AccessLogFormat) -> String {
+        let quoted = |s: &Option<String>| match s {
+            Some(s) => format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => String::from("\"-\""),
+        };
+
+        let common = format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.protocol,
+            self.status,
+            self.bytes
+        );
+
+        match format {
+            AccessLogFormat::Combined => format!(
+                "{} {} {}",
+                common,
+                quoted(&self.referrer),
+                quoted(&self.user_agent)
+            ),
+            AccessLogFormat::Json => serde_json::json!({
+                "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                "client_ip": self.client_ip,
+                "method": self.method,
+                "path": self.path,
+                "protocol": self.protocol,
+                "status": self.status,
+                "bytes": self.bytes,
+                "duration_ms": (self.duration_ms * 1000.0).round() / 1000.0,
+                "referrer": self.referrer,
+                "user_agent": self.user_agent,
+            })
+            .to_string(),
+            _ => common,
+        }
+    }
+}
+
+/// Replace the private tokens (UUIDs) in the path (or URL) with a placeholder.
+fn redact(path: &str) -> String {
+    let (path, query) = match path.split_once('?') {
+        Some((p, q)) => (p, Some(q)),
+        None => (path, None),
+    };
+
+    let mut redacted = path
+        .split('/')
+        .map(|s| match s.parse::<Uuid>() {
+            Ok(_) => REDACTED,
+            Err(_) => s,
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    if let Some(q) = query {
+        redacted.push('?');
+        redacted.push_str(q);
+    }
+
+    redacted
+}
+
+/// File which is rotated (as `<file>.1`, `<file>.2`, etc.) once it's too large.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    size: u64,
+    writer: BufWriter<File>,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RotatingFile {
+            path: path.to_owned(),
+            max_size,
+            keep,
+            size: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        *self = RotatingFile::open(&self.path, self.max_size, self.keep)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 >= self.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.writer, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Middleware which logs every request (after its response has been sent) to
+/// stdout or a rotating file.
+pub struct AccessLog {
+    sender: Sender<Entry>,
+}
+
+impl AccessLog {
+    /// Start the writer of the access log (`None` if it's disabled).
+    pub fn new(settings: &AccessLogSettings) -> io::Result<Option<Self>> {
+        let format = settings.format;
+        if format == AccessLogFormat::Off {
+            return Ok(None);
+        }
+
+        let mut file = match &settings.file {
+            Some(p) => Some(RotatingFile::open(
+                p,
+                settings.max_size_mb << 20,
+                settings.keep,
+            )?),
+            None => None,
+        };
+
+        let (tx, rx) = mpmc::unbounded::<Entry>();
+        thread::Builder::new()
+            .name(String::from("access-log"))
+            .spawn(move || {
+                let stdout = io::stdout();
+                for entry in rx.iter() {
+                    let line = entry.format(format);
+                    let result = match file.as_mut() {
+                        // Don't hold back the lines while there's nothing else to write.
+                        Some(f) if rx.is_empty() => {
+                            f.write_line(&line).and_then(|_| f.writer.flush())
+                        }
+                        Some(f) => f.write_line(&line),
+                        None => writeln!(stdout.lock(), "{}", line),
+                    };
+
+                    if let Err(e) = result {
+                        error!("Cannot write access log: {}", e);
+                    }
+                }
+            })?;
+
+        Ok(Some(AccessLog { sender: tx }))
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AccessLog {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let header = |name| req.header(name).map(|v| v.as_str().to_owned());
+        let url = req.url();
+        let path = match url.query() {
+            Some(q) => format!("{}?{}", url.path(), q),
+            None => url.path().to_owned(),
+        };
+
+        let client_ip = proxy::client_ip(&req)
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| String::from("-"));
+        let mut entry = Entry {
+            time: Utc::now(),
+            client_ip,
+            method: req.method().to_string(),
+            path: redact(&path),
+            protocol: req
+                .version()
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| String::from("HTTP/1.1")),
+            status: 0,
+            bytes: 0,
+            duration_ms: 0.0,
+            referrer: header("Referer").map(|r| redact(&r)),
+            user_agent: header("User-Agent"),
+        };
+
+        let mut resp = next.run(req).await;
+        entry.status = resp.status() as u16;
+        let sender = self.sender.clone();
+        util::observe_body(&mut resp, move |bytes| {
+            entry.bytes = bytes;
+            entry.duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+            let _ = sender.send(entry);
+        });
+        Ok(resp)
+    }
+}
@@ -0,0 +1,172 @@
+use crate::cidr::Cidr;
+use crate::proxy;
+use crate::server;
+use crate::settings::AccessControlRule;
+use crate::watcher::PrivateAccess;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use std::cmp::Reverse;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Rule for the client IPs, which are allowed or denied if they're in any of the ranges.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum AccessRule {
+    Allow(Vec<Cidr>),
+    Deny(Vec<Cidr>),
+}
+
+/// Whether the client is allowed by the rules, where the first rule with a matching
+/// range applies (and clients without any matching rules are allowed).
+pub fn is_allowed(rules: &[AccessRule], ip: &IpAddr) -> bool {
+    for rule in rules {
+        let (allow, ranges) = match rule {
+            AccessRule::Allow(r) => (true, r),
+            AccessRule::Deny(r) => (false, r),
+        };
+
+        if ranges.iter().any(|c| c.contains(ip)) {
+            return allow;
+        }
+    }
+
+    true
+}
+
+/// Middleware which denies the clients based on the rules of the path prefixes,
+/// and those of the private entries (for their links and WebDAV shares). It's shared
+/// with the search, which shouldn't reveal the pages that the client can't request.
+#[derive(Clone)]
+pub struct AccessControl {
+    /// Rules for the prefixes (longest first).
+    rules: Arc<Vec<AccessControlRule>>,
+    private: PrivateAccess,
+    denied_status: StatusCode,
+}
+
+impl AccessControl {
+    pub fn new(rules: &[AccessControlRule], private: PrivateAccess, denied_status: u16) -> Self {
+        let mut rules = rules.to_vec();
+        rules.sort_by_key(|r| Reverse(r.prefix.len()));
+        AccessControl {
+            rules: Arc::new(rules),
+            private,
+            denied_status: StatusCode::try_from(denied_status).unwrap_or(StatusCode::Forbidden),
+        }
+    }
+
+    /// Whether the client is allowed to request the (served) path.
+    pub fn is_allowed(&self, path: &str, ip: Option<IpAddr>) -> bool {
+        // Clients which aren't known are denied by any rules.
+        let check = |rules: &[AccessRule]| ip.map_or(rules.is_empty(), |ip| is_allowed(rules, &ip));
+        let prefix_allows = self
+            .rules
+            .iter()
+            .find(|r| server::has_path_prefix(path, &r.prefix))
+            .is_none_or(|r| check(&r.rules));
+        if !prefix_allows {
+            return false;
+        }
+
+        match server::private_token(path).and_then(|t| t.parse::<Uuid>().ok()) {
+            Some(id) => self
+                .private
+                .read()
+                .expect("access lock poisoned")
+                .get(&id)
+                .is_none_or(|rules| check(rules)),
+            None => true,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AccessControl {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = server::served_path(&req);
+        let ip = proxy::client_ip(&req);
+        if !self.is_allowed(&path, ip) {
+            debug!("Denied access to {} for {:?}", path, ip);
+            return Ok(Response::new(self.denied_status));
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessControl, AccessRule};
+    use crate::settings::AccessControlRule;
+    use std::net::IpAddr;
+    use uuid::Uuid;
+
+    const TOKEN: &str = "29579458-8a68-43d0-be6f-3df2e1cb406a";
+
+    fn rules(allowed: &str) -> Vec<AccessRule> {
+        vec![
+            AccessRule::Allow(vec![allowed.parse().expect("valid range")]),
+            AccessRule::Deny(vec!["0.0.0.0/0".parse().expect("valid range")]),
+        ]
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let prefixes = [
+            AccessControlRule {
+                prefix: "/admin".into(),
+                rules: rules("10.0.0.0/8"),
+            },
+            AccessControlRule {
+                prefix: "/admin/public/".into(),
+                rules: vec![],
+            },
+        ];
+        let acl = AccessControl::new(&prefixes, Default::default(), 403);
+        acl.private
+            .write()
+            .expect("access lock")
+            .insert(TOKEN.parse::<Uuid>().expect("UUID"), rules("192.0.2.0/24"));
+
+        let private = format!("/private/{}/a.txt", TOKEN);
+        let share = format!("/_dav/share/{}/a.txt", TOKEN);
+        let cases: &[(&str, Option<&str>, bool)] = &[
+            ("/admin", Some("10.1.2.3"), true),
+            ("/admin", Some("192.0.2.1"), false),
+            ("/admin/", Some("192.0.2.1"), false),
+            ("/admin/users", Some("192.0.2.1"), false),
+            // clients which aren't known are denied by any rules
+            ("/admin/users", None, false),
+            ("/index.html", None, true),
+            // prefixes match whole segments
+            ("/administrator", Some("192.0.2.1"), true),
+            ("/admin.html", Some("192.0.2.1"), true),
+            // the longest prefix applies
+            ("/admin/public", Some("192.0.2.1"), true),
+            ("/admin/public/a.png", None, true),
+            ("/admin/publicity", Some("192.0.2.1"), false),
+            // private entries
+            (&private, Some("192.0.2.1"), true),
+            (&private, Some("10.1.2.3"), false),
+            (&share, Some("10.1.2.3"), false),
+            (
+                "/private/00000000-0000-0000-0000-000000000000/a.txt",
+                None,
+                true,
+            ),
+        ];
+
+        for (path, ip, expected) in cases {
+            let ip = ip.map(|ip| ip.parse::<IpAddr>().expect("valid IP"));
+            assert_eq!(
+                acl.is_allowed(path, ip),
+                *expected,
+                "{:?} for {:?}",
+                path,
+                ip
+            );
+        }
+    }
+}
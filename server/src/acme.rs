@@ -0,0 +1,663 @@
+use crate::settings::{AcmeSettings, CertificateSettings};
+use crate::tls::CertResolver;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use chrono::Utc;
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::crypto::ring as provider;
+use rustls::{ClientConfig, RootCertStore};
+use serde_json::{json, Value};
+use tide::{Endpoint, Request, Response, StatusCode};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+pub const CHALLENGE_PATH_PREFIX: &str = "/.well-known/acme-challenge";
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+/// Certificate chain along with its key (in a single file, so that they're replaced together).
+const CERT_FILE: &str = "certificate.pem";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+/// Delay before the first check, so that the listeners are up for the challenges.
+const START_DELAY: Duration = Duration::from_secs(2);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Errors in obtaining a certificate.
+#[derive(Debug)]
+pub enum AcmeError {
+    Io(io::Error),
+    Http(String),
+    /// Problem document (RFC 7807) returned by the server.
+    Problem(u16, Value),
+    Protocol(String),
+}
+
+impl fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcmeError::Io(e) => write!(f, "I/O error: {}", e),
+            AcmeError::Http(e) => write!(f, "HTTP error: {}", e),
+            AcmeError::Problem(status, p) => write!(f, "server returned {}: {}", status, p),
+            AcmeError::Protocol(e) => write!(f, "protocol error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for AcmeError {
+    fn from(e: io::Error) -> Self {
+        AcmeError::Io(e)
+    }
+}
+
+impl From<rcgen::Error> for AcmeError {
+    fn from(e: rcgen::Error) -> Self {
+        AcmeError::Protocol(e.to_string())
+    }
+}
+
+/// Key authorizations for the pending HTTP-01 challenges (by token).
+#[derive(Clone, Default)]
+pub struct Challenges(Arc<RwLock<HashMap<String, String>>>);
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for Challenges {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let token = req.url().path().rsplit('/').next().unwrap_or("");
+        let challenges = self.0.read().expect("challenges lock poisoned");
+        Ok(match challenges.get(token) {
+            Some(key_auth) => Response::builder(StatusCode::Ok)
+                .content_type(http_types::mime::PLAIN)
+                .body(key_auth.as_str())
+                .build(),
+            None => Response::new(StatusCode::NotFound),
+        })
+    }
+}
+
+/// Certificate (and its key) which is managed by ACME.
+pub fn certificate(settings: &AcmeSettings) -> CertificateSettings {
+    let path = settings.dir.join(CERT_FILE);
+    CertificateSettings {
+        hosts: settings.domains.clone(),
+        cert: path.clone(),
+        key: path,
+    }
+}
+
+/// Write the file atomically (so that the watchers never see partial files), with
+/// access only for the owner (as the files have private keys).
+fn write_file(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    // The mode only applies to new files, so a leftover one can't be reused.
+    match fs::remove_file(&tmp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Public key (JWK) of the account key.
+fn jwk(key: &EcdsaKeyPair) -> Value {
+    // Uncompressed point (0x04 || x || y)
+    let point = key.public_key().as_ref();
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+        "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+    })
+}
+
+/// Thumbprint of the public key (RFC 7638) for the key authorizations.
+fn thumbprint(jwk: &Value) -> String {
+    // Members should be in lexicographic order without whitespace.
+    let jwk = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+        jwk["x"], jwk["y"]
+    );
+    let digest = ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes());
+    URL_SAFE_NO_PAD.encode(digest.as_ref())
+}
+
+/// Sign the payload (or an empty payload for POST-as-GET) as a flattened JWS (RFC 7515).
+fn sign(
+    key: &EcdsaKeyPair,
+    rng: &SystemRandom,
+    protected: &Value,
+    payload: Option<&Value>,
+) -> Result<Value, AcmeError> {
+    let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+    let payload = payload
+        .map(|p| URL_SAFE_NO_PAD.encode(p.to_string()))
+        .unwrap_or_default();
+    let signature = key
+        .sign(rng, format!("{}.{}", protected, payload).as_bytes())
+        .map_err(|_| AcmeError::Protocol(String::from("cannot sign request")))?;
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+    }))
+}
+
+/// Seconds left for the certificate to expire, or `None` if it doesn't exist
+/// (or if it doesn't cover all the domains).
+fn time_to_expiry(path: &Path, domains: &[String]) -> Option<i64> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let der = rustls_pemfile::certs(&mut reader).next()?.ok()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&der).ok()?;
+    let names = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|ext| {
+            ext.value
+                .general_names
+                .iter()
+                .filter_map(|n| match n {
+                    x509_parser::extensions::GeneralName::DNSName(d) => Some(d.to_lowercase()),
+                    _ => None,
+                })
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+
+    if domains.iter().any(|d| !names.contains(&d.to_lowercase())) {
+        return None;
+    }
+
+    Some(cert.validity().not_after.timestamp() - Utc::now().timestamp())
+}
+
+/// Reply for a request to the ACME server.
+struct Reply {
+    location: Option<String>,
+    retry_after: Option<Duration>,
+    body: Value,
+    text: String,
+}
+
+/// ACME (RFC 8555) client for obtaining certificates through HTTP-01 challenges.
+pub struct AcmeClient {
+    settings: AcmeSettings,
+    challenges: Challenges,
+    agent: ureq::Agent,
+    rng: SystemRandom,
+    key: EcdsaKeyPair,
+    /// Public key of the account (JWK).
+    jwk: Value,
+    directory: Value,
+    account: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Create a client with the account key from the storage (or a new one).
+    pub fn new(settings: &AcmeSettings, challenges: Challenges) -> Result<Self, AcmeError> {
+        fs::create_dir_all(&settings.dir)?;
+        let key_path = settings.dir.join(ACCOUNT_KEY_FILE);
+        let pem = match fs::read_to_string(&key_path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("Generating ACME account key in {}", key_path.display());
+                let pem = KeyPair::generate()?.serialize_pem();
+                write_file(&key_path, &pem)?;
+                pem
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let rng = SystemRandom::new();
+        let pkcs8 = KeyPair::from_pem(&pem)?.serialize_der();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| AcmeError::Protocol(format!("unsupported account key: {}", e)))?;
+        let jwk = jwk(&key);
+
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+
+        if let Some(path) = &settings.ca_cert {
+            let mut reader = BufReader::new(File::open(path)?);
+            for cert in rustls_pemfile::certs(&mut reader) {
+                roots
+                    .add(cert?)
+                    .map_err(|e| AcmeError::Protocol(format!("invalid CA certificate: {}", e)))?;
+            }
+        }
+
+        let tls = ClientConfig::builder_with_provider(Arc::new(provider::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| AcmeError::Protocol(e.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(AcmeClient {
+            settings: settings.clone(),
+            challenges,
+            agent: ureq::AgentBuilder::new()
+                .timeout(REQUEST_TIMEOUT)
+                .tls_config(Arc::new(tls))
+                .build(),
+            rng,
+            key,
+            jwk,
+            directory: Value::Null,
+            account: None,
+            nonce: None,
+        })
+    }
+
+    /// URL of the given resource from the directory.
+    fn resource(&self, name: &str) -> Result<String, AcmeError> {
+        self.directory[name]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| AcmeError::Protocol(format!("directory has no {}", name)))
+    }
+
+    fn reply(&mut self, resp: Result<ureq::Response, ureq::Error>) -> Result<Reply, AcmeError> {
+        let (status, resp) = match resp {
+            Ok(r) => (None, r),
+            Err(ureq::Error::Status(status, r)) => (Some(status), r),
+            Err(e) => return Err(AcmeError::Http(e.to_string())),
+        };
+
+        if let Some(nonce) = resp.header("Replay-Nonce") {
+            self.nonce = Some(nonce.to_owned());
+        }
+
+        let location = resp.header("Location").map(String::from);
+        let retry_after = resp
+            .header("Retry-After")
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs);
+        let text = resp.into_string()?;
+        let body = serde_json::from_str(&text).unwrap_or(Value::Null);
+        match status {
+            Some(s) => Err(AcmeError::Problem(s, body)),
+            None => Ok(Reply {
+                location,
+                retry_after,
+                body,
+                text,
+            }),
+        }
+    }
+
+    fn fetch_nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+
+        let url = self.resource("newNonce")?;
+        let resp = self.agent.head(&url).call();
+        self.reply(resp)?;
+        self.nonce
+            .take()
+            .ok_or_else(|| AcmeError::Protocol(String::from("no nonce from server")))
+    }
+
+    /// Sign and send the payload (or an empty payload for POST-as-GET) to the URL.
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Reply, AcmeError> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.fetch_nonce()?,
+                "url": url,
+            });
+            match &self.account {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+
+            let body = sign(&self.key, &self.rng, &protected, payload)?;
+            let resp = self
+                .agent
+                .post(url)
+                .set("Content-Type", "application/jose+json")
+                .send_string(&body.to_string());
+            match self.reply(resp) {
+                // Nonces can expire, so they're retried once.
+                Err(AcmeError::Problem(_, p)) if p["type"] == BAD_NONCE && !retried => {
+                    retried = true
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Poll the resource until its status is no longer the given one.
+    fn poll(&mut self, url: &str, pending: &[&str]) -> Result<Value, AcmeError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let reply = self.post(url, None)?;
+            let status = reply.body["status"].as_str().unwrap_or("");
+            if !pending.contains(&status) {
+                return Ok(reply.body);
+            }
+
+            thread::sleep(reply.retry_after.unwrap_or(POLL_INTERVAL));
+        }
+
+        Err(AcmeError::Protocol(format!(
+            "timed out waiting for {}",
+            url
+        )))
+    }
+
+    /// Find (or create) the account for the key.
+    fn login(&mut self) -> Result<(), AcmeError> {
+        let resp = self.agent.get(&self.settings.directory).call();
+        self.directory = self.reply(resp)?.body;
+        if self.account.is_some() {
+            return Ok(());
+        }
+
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = &self.settings.contact {
+            payload["contact"] = json!([contact]);
+        }
+
+        let url = self.resource("newAccount")?;
+        let reply = self.post(&url, Some(&payload))?;
+        self.account = Some(
+            reply
+                .location
+                .ok_or_else(|| AcmeError::Protocol(String::from("no account URL")))?,
+        );
+        Ok(())
+    }
+
+    /// Respond to the HTTP-01 challenge of the authorization.
+    fn authorize(&mut self, url: &str) -> Result<(), AcmeError> {
+        let authz = self.post(url, None)?.body;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+
+        let challenge = authz["challenges"]
+            .as_array()
+            .and_then(|c| c.iter().find(|c| c["type"] == "http-01"))
+            .ok_or_else(|| AcmeError::Protocol(String::from("no HTTP-01 challenge")))?;
+        let (token, challenge_url) = match (challenge["token"].as_str(), challenge["url"].as_str())
+        {
+            (Some(t), Some(u)) => (t.to_owned(), u.to_owned()),
+            _ => return Err(AcmeError::Protocol(String::from("invalid challenge"))),
+        };
+
+        info!(
+            "Responding to challenge for {}",
+            authz["identifier"]["value"]
+        );
+        let key_auth = format!("{}.{}", token, thumbprint(&self.jwk));
+        self.challenges
+            .0
+            .write()
+            .expect("challenges lock poisoned")
+            .insert(token.clone(), key_auth);
+
+        let result = self
+            .post(&challenge_url, Some(&json!({})))
+            .and_then(|_| self.poll(url, &["pending"]));
+        self.challenges
+            .0
+            .write()
+            .expect("challenges lock poisoned")
+            .remove(&token);
+
+        let authz = result?;
+        match authz["status"].as_str() {
+            Some("valid") => Ok(()),
+            _ => Err(AcmeError::Protocol(format!(
+                "authorization failed: {}",
+                authz["challenges"]
+            ))),
+        }
+    }
+
+    /// Order a new certificate for the domains and store it (along with its key).
+    pub fn issue(&mut self) -> Result<(), AcmeError> {
+        self.login()?;
+        let identifiers = self
+            .settings
+            .domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect::<Vec<_>>();
+        let url = self.resource("newOrder")?;
+        let reply = self.post(&url, Some(&json!({ "identifiers": identifiers })))?;
+        let order_url = reply
+            .location
+            .ok_or_else(|| AcmeError::Protocol(String::from("no order URL")))?;
+
+        let authorizations = reply.body["authorizations"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|u| u.as_str().map(String::from))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        for authz in authorizations {
+            self.authorize(&authz)?;
+        }
+
+        let order = self.poll(&order_url, &["pending"])?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or_else(|| AcmeError::Protocol(format!("order not ready: {}", order)))?;
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(self.settings.domains.clone())?;
+        params.distinguished_name = DistinguishedName::new();
+        let csr = params.serialize_request(&key)?;
+        self.post(
+            finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) })),
+        )?;
+
+        let order = self.poll(&order_url, &["ready", "processing"])?;
+        let cert_url = match (order["status"].as_str(), order["certificate"].as_str()) {
+            (Some("valid"), Some(u)) => u.to_owned(),
+            _ => return Err(AcmeError::Protocol(format!("order failed: {}", order))),
+        };
+
+        let chain = self.post(&cert_url, None)?.text;
+        // Both are in the same file, so that the resolver never loads a mismatched pair.
+        let path = certificate(&self.settings).cert;
+        write_file(&path, &format!("{}{}", key.serialize_pem(), chain))?;
+        info!(
+            "Obtained certificate for {}",
+            self.settings.domains.join(", ")
+        );
+        Ok(())
+    }
+}
+
+/// Keep the certificate renewed in the background, and load it into the resolver.
+pub fn start(settings: &AcmeSettings, challenges: Challenges, resolver: Arc<CertResolver>) {
+    let settings = settings.clone();
+    let cert_path: PathBuf = certificate(&settings).cert;
+    let renew_before = (settings.renew_before_days * 24 * 60 * 60) as i64;
+    thread::spawn(move || {
+        thread::sleep(START_DELAY);
+        let mut client = None;
+        loop {
+            let remaining = time_to_expiry(&cert_path, &settings.domains);
+            let wait = match remaining {
+                Some(r) if r > renew_before => {
+                    Duration::from_secs((r - renew_before) as u64).min(CHECK_INTERVAL)
+                }
+                _ => {
+                    info!("Obtaining certificate for {}", settings.domains.join(", "));
+                    let result = match &mut client {
+                        Some(c) => Ok(c),
+                        None => {
+                            AcmeClient::new(&settings, challenges.clone()).map(|c| client.insert(c))
+                        }
+                    };
+
+                    match result.and_then(|c| c.issue()) {
+                        Ok(()) => {
+                            resolver.reload();
+                            CHECK_INTERVAL
+                        }
+                        Err(e) => {
+                            error!("Cannot obtain certificate: {}", e);
+                            RETRY_INTERVAL
+                        }
+                    }
+                }
+            };
+
+            thread::sleep(wait);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{jwk, sign, thumbprint, write_file};
+    use crate::tls;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine as _;
+    use rcgen::{CertificateParams, KeyPair};
+    use ring::rand::SystemRandom;
+    use ring::signature::{self, EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// EC key from RFC 7517 (appendix A.2).
+    const D: &str = "870MB6gfuTJ4HtUnUvYMyJpr5eUZNP4Bk43bVdj3eAE";
+    const X: &str = "MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4";
+    const Y: &str = "4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM";
+
+    fn decode(s: &str) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(s).expect("base64url")
+    }
+
+    fn key() -> EcdsaKeyPair {
+        let point = [vec![4], decode(X), decode(Y)].concat();
+        EcdsaKeyPair::from_private_key_and_public_key(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &decode(D),
+            &point,
+            &SystemRandom::new(),
+        )
+        .expect("valid key")
+    }
+
+    #[test]
+    fn test_jwk_thumbprint() {
+        let jwk = jwk(&key());
+        assert_eq!(jwk, json!({ "crv": "P-256", "kty": "EC", "x": X, "y": Y }));
+        assert_eq!(
+            thumbprint(&jwk),
+            "cn-I_WNMClehiVp51i_0VpOENW1upEerA8sEam5hn-s"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let key = key();
+        let protected = json!({
+            "alg": "ES256",
+            "nonce": "abc",
+            "url": "https://acme.test/new-order",
+            "kid": "https://acme.test/acct/1",
+        });
+        let payload = json!({ "identifiers": [{ "type": "dns", "value": "waffles.space" }] });
+        for payload in [Some(&payload), None] {
+            let jws = sign(&key, &SystemRandom::new(), &protected, payload).expect("signed");
+            let field = |name| jws[name].as_str().expect("string field");
+
+            let decoded: Value =
+                serde_json::from_slice(&decode(field("protected"))).expect("JSON header");
+            assert_eq!(decoded, protected);
+            match payload {
+                Some(p) => assert_eq!(
+                    serde_json::from_slice::<Value>(&decode(field("payload"))).expect("JSON"),
+                    *p
+                ),
+                // POST-as-GET has an empty payload (rather than an empty object).
+                None => assert_eq!(field("payload"), ""),
+            }
+
+            let input = format!("{}.{}", field("protected"), field("payload"));
+            signature::UnparsedPublicKey::new(
+                &signature::ECDSA_P256_SHA256_FIXED,
+                key.public_key(),
+            )
+            .verify(input.as_bytes(), &decode(field("signature")))
+            .expect("valid signature");
+        }
+    }
+
+    #[test]
+    fn test_write_certificate() {
+        let dir = std::env::temp_dir().join(format!("acme-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("test dir");
+        let path = dir.join("certificate.pem");
+        // A leftover temporary file (with a wider mode) isn't reused.
+        fs::write(path.with_extension("tmp"), "").expect("leftover file");
+
+        let key = KeyPair::generate().expect("key");
+        let cert = CertificateParams::new(vec![String::from("waffles.space")])
+            .and_then(|p| p.self_signed(&key))
+            .expect("certificate");
+        let result = write_file(&path, &format!("{}{}", key.serialize_pem(), cert.pem()));
+        let mode = fs::metadata(&path).map(|m| m.permissions().mode() & 0o777);
+        let loaded = tls::load_certificate(&path, &path);
+        let leftover = path.with_extension("tmp").exists();
+        fs::remove_dir_all(&dir).expect("test dir removed");
+
+        result.expect("written");
+        assert_eq!(mode.expect("metadata"), 0o600);
+        assert!(!leftover);
+        let loaded = loaded.expect("loaded");
+        assert_eq!(loaded.cert.len(), 1);
+        assert_eq!(loaded.cert[0].as_ref(), cert.der().as_ref());
+    }
+
+    #[test]
+    fn test_mismatched_key() {
+        let dir = std::env::temp_dir().join(format!("acme-mismatch-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("test dir");
+        let path = dir.join("certificate.pem");
+
+        let key = KeyPair::generate().expect("key");
+        let cert = CertificateParams::new(vec![String::from("waffles.space")])
+            .and_then(|p| p.self_signed(&key))
+            .expect("certificate");
+        let other = KeyPair::generate().expect("key");
+        let result = write_file(&path, &format!("{}{}", other.serialize_pem(), cert.pem()));
+        let loaded = tls::load_certificate(&path, &path);
+        fs::remove_dir_all(&dir).expect("test dir removed");
+
+        result.expect("written");
+        assert!(matches!(loaded, Err(tls::CertificateError::Mismatch(_))));
+    }
+}
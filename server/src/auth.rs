@@ -0,0 +1,42 @@
+use crate::settings;
+use http_types::auth::BasicAuth;
+use http_types::headers::{AUTHORIZATION, WWW_AUTHENTICATE};
+use tide::{Request, Response, StatusCode};
+
+/// Compare two byte slices in constant time (for the given lengths).
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks whether the request carries the admin token, either as a bearer token
+/// or as the password of basic auth (for clients which only support the latter).
+///
+/// Authenticated endpoints are disabled altogether if there's no token.
+pub fn is_authorized<State>(req: &Request<State>) -> bool {
+    let token = match settings::get().admin_token.as_ref() {
+        Some(t) => t.as_bytes(),
+        None => return false,
+    };
+
+    if let Some(bearer) = req
+        .header(AUTHORIZATION)
+        .and_then(|v| v.as_str().strip_prefix("Bearer "))
+    {
+        return constant_time_eq(bearer.trim().as_bytes(), token);
+    }
+
+    match BasicAuth::from_headers(req) {
+        Ok(Some(auth)) => constant_time_eq(auth.password().as_bytes(), token),
+        _ => false,
+    }
+}
+
+/// Response for requests which failed authentication.
+pub fn unauthorized() -> Response {
+    Response::builder(StatusCode::Unauthorized)
+        .header(
+            WWW_AUTHENTICATE,
+            "Basic realm=\"waffles\", charset=\"UTF-8\"",
+        )
+        .build()
+}
@@ -0,0 +1,102 @@
+use crossbeam_channel::{self as mpmc, Sender};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const WATCHER_DEBOUNCE_DURATION: Duration = Duration::from_secs(2);
+
+/// A change in the watched root (with paths relative to that root).
+#[derive(Clone, Debug)]
+pub enum Change {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed(PathBuf, PathBuf),
+}
+
+impl Change {
+    /// All the paths affected by this change.
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Change::Created(p) | Change::Modified(p) | Change::Removed(p) => vec![p],
+            Change::Renamed(old, new) => vec![old, new],
+        }
+    }
+}
+
+/// Internal bus for publishing the changes in the public root to its consumers
+/// (caches, indexes, etc.)
+#[derive(Clone, Default)]
+pub struct ChangeBus {
+    subscribers: Arc<Mutex<Vec<Sender<Change>>>>,
+}
+
+impl ChangeBus {
+    /// Call the function for every change (in a separate thread).
+    pub fn listen(&self, name: &str, f: impl Fn(&Change) + Send + 'static) {
+        let (tx, rx) = mpmc::unbounded();
+        self.subscribers
+            .lock()
+            .expect("subscribers lock poisoned")
+            .push(tx);
+
+        thread::Builder::new()
+            .name(format!("bus-{}", name))
+            .spawn(move || {
+                for change in rx {
+                    f(&change);
+                }
+            })
+            .expect("spawning listener");
+    }
+
+    /// Send the change to all the subscribers.
+    pub fn publish(&self, change: Change) {
+        debug!("Publishing {:?}", change);
+        self.subscribers
+            .lock()
+            .expect("subscribers lock poisoned")
+            .retain(|tx| tx.send(change.clone()).is_ok());
+    }
+
+    /// Start watching the root and publish its changes.
+    pub fn watch(&self, root: impl AsRef<Path>) {
+        let root = root.as_ref();
+        // Events have canonical paths, so the root should be canonical for comparison.
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let (tx, rx) = mpsc::channel();
+        let mut watcher =
+            notify::watcher(tx, WATCHER_DEBOUNCE_DURATION).expect("cannot create watcher");
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .expect("cannot watch path");
+
+        let bus = self.clone();
+        thread::spawn(move || {
+            let _watcher = watcher; // keep the watcher alive with the thread.
+            let rel = |p: &Path| p.strip_prefix(&root).ok().map(Path::to_path_buf);
+            for event in rx {
+                let change = match event {
+                    DebouncedEvent::Create(ref p) => rel(p).map(Change::Created),
+                    DebouncedEvent::Write(ref p) => rel(p).map(Change::Modified),
+                    DebouncedEvent::Remove(ref p) => rel(p).map(Change::Removed),
+                    DebouncedEvent::Rename(ref old, ref new) => match (rel(old), rel(new)) {
+                        (Some(o), Some(n)) => Some(Change::Renamed(o, n)),
+                        (None, Some(n)) => Some(Change::Created(n)),
+                        (Some(o), None) => Some(Change::Removed(o)),
+                        (None, None) => None,
+                    },
+                    _ => None,
+                };
+
+                if let Some(c) = change {
+                    bus.publish(c);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,91 @@
+use ipnet::IpNet;
+
+use std::fmt;
+use std::net::{AddrParseError, IpAddr};
+use std::str::FromStr;
+
+/// Range of IP addresses (IPv4 or IPv6) in CIDR notation, where a single address
+/// is the range of just that address.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr(IpNet);
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if self.0.contains(ip) {
+            return true;
+        }
+
+        // IPv4-mapped IPv6 addresses (from dual-stack sockets) are also in the IPv4 ranges.
+        match (self.0, ip) {
+            (IpNet::V4(net), IpAddr::V6(v6)) => {
+                v6.to_ipv4_mapped().is_some_and(|v4| net.contains(&v4))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        s.parse::<IpNet>()
+            .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+            .map(|n| Cidr(n.trunc()))
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+            .map_err(|_| format!("{:?} is not a valid IP address or CIDR range", s))
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(c: Cidr) -> Self {
+        c.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_contains() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("10.0.0.0/8", "10.1.2.3", true),
+            ("10.0.0.0/8", "11.1.2.3", false),
+            ("10.1.2.3", "10.1.2.3", true),
+            ("10.1.2.3", "10.1.2.4", false),
+            ("2001:db8::/32", "2001:db8:1::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("::1", "::1", true),
+            // IPv4-mapped addresses are in the IPv4 ranges, and in the IPv6 ones.
+            ("10.0.0.0/8", "::ffff:10.1.2.3", true),
+            ("10.0.0.0/8", "::ffff:11.1.2.3", false),
+            ("::ffff:0:0/96", "::ffff:10.1.2.3", true),
+            ("::ffff:0:0/96", "10.1.2.3", false),
+            ("0.0.0.0/0", "2001:db8::1", false),
+            ("::/0", "10.1.2.3", false),
+        ];
+
+        for (cidr, ip, expected) in cases {
+            let c = cidr.parse::<Cidr>().unwrap();
+            let ip = ip.parse::<IpAddr>().unwrap();
+            assert_eq!(c.contains(&ip), *expected, "{} contains {}", cidr, ip);
+        }
+    }
+}
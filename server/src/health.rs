@@ -0,0 +1,215 @@
+use crate::shutdown::Shutdown;
+use http_types::Method;
+use tide::{Body, Endpoint, Middleware, Next, Request, Response, StatusCode};
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const HEALTH_PATH: &str = "/healthz";
+pub const READY_PATH: &str = "/readyz";
+
+/// Time without a heartbeat (in addition to the interval) after which the watcher is stale.
+const WATCHER_STALE_AFTER: Duration = Duration::from_secs(60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness of a background thread, which beats on every iteration.
+#[derive(Clone)]
+pub struct Heartbeat {
+    /// Time of the last beat (`None` once the thread has stopped).
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            last: Arc::new(Mutex::new(Some(Instant::now()))),
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.last.lock().expect("heartbeat lock") = Some(Instant::now());
+    }
+
+    /// Get a guard which marks the thread as stopped when it's dropped (including
+    /// when the thread panics).
+    pub fn guard(&self) -> HeartbeatGuard {
+        HeartbeatGuard(self.clone())
+    }
+
+    /// Time since the last beat (`None` if the thread has stopped).
+    fn elapsed(&self) -> Option<Duration> {
+        self.last
+            .lock()
+            .expect("heartbeat lock")
+            .map(|t| t.elapsed())
+    }
+}
+
+pub struct HeartbeatGuard(Heartbeat);
+
+impl Drop for HeartbeatGuard {
+    fn drop(&mut self) {
+        *self.0.last.lock().expect("heartbeat lock") = None;
+    }
+}
+
+/// Endpoint for the liveness of the server (which is up, if it responds).
+pub async fn healthz<State>(_req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok).body("ok").build())
+}
+
+/// Endpoint for the readiness of the server, which checks the public root, the
+/// watcher of the private root and the config of the private links.
+pub struct Readiness {
+    source: PathBuf,
+    config: PathBuf,
+    watcher: Heartbeat,
+    watcher_interval: Duration,
+    shutdown: Shutdown,
+}
+
+impl Readiness {
+    pub fn new(
+        source: &Path,
+        config: &Path,
+        watcher: Heartbeat,
+        watcher_interval: Duration,
+        shutdown: Shutdown,
+    ) -> Self {
+        Readiness {
+            source: source.to_owned(),
+            config: config.to_owned(),
+            watcher,
+            watcher_interval,
+            shutdown,
+        }
+    }
+
+    /// Results of all the checks (`None` if the check has passed).
+    fn checks(&self) -> BTreeMap<&'static str, Option<String>> {
+        let mut checks = BTreeMap::new();
+        checks.insert(
+            "source",
+            fs::read_dir(&self.source)
+                .err()
+                .map(|e| format!("cannot read {}: {}", self.source.display(), e)),
+        );
+        checks.insert(
+            "watcher",
+            match self.watcher.elapsed() {
+                None => Some(String::from("stopped")),
+                Some(d) if d > self.watcher_interval + WATCHER_STALE_AFTER => {
+                    Some(format!("no heartbeat for {}s", d.as_secs()))
+                }
+                Some(_) => None,
+            },
+        );
+        checks.insert(
+            "config",
+            OpenOptions::new()
+                .append(true)
+                .open(&self.config)
+                .err()
+                .map(|e| format!("cannot write {}: {}", self.config.display(), e)),
+        );
+        checks.insert(
+            "shutdown",
+            self.shutdown
+                .is_stopping()
+                .then(|| String::from("shutting down")),
+        );
+        checks
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for Readiness {
+    async fn call(&self, _req: Request<State>) -> tide::Result {
+        let checks = self.checks();
+        let failures = checks
+            .iter()
+            .filter_map(|(name, e)| e.as_ref().map(|e| (name, e)))
+            .collect::<BTreeMap<_, _>>();
+        for (name, e) in &failures {
+            warn!("Readiness check for {} failed: {}", name, e);
+        }
+
+        let mut resp = Response::new(if failures.is_empty() {
+            StatusCode::Ok
+        } else {
+            StatusCode::ServiceUnavailable
+        });
+        // Probes can come from anywhere, so the details are only in the logs.
+        resp.set_body(Body::from_json(&serde_json::json!({
+            "ready": failures.is_empty(),
+            "checks": checks
+                .iter()
+                .map(|(name, e)| (*name, if e.is_some() { "failed" } else { "ok" }))
+                .collect::<BTreeMap<_, _>>(),
+        }))?);
+        Ok(resp)
+    }
+}
+
+/// Middleware which responds to the probes before the access control, rate
+/// limits and protection (which shouldn't make the server look down).
+pub struct Probes(pub Readiness);
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Probes {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !matches!(req.method(), Method::Get | Method::Head) {
+            return Ok(next.run(req).await);
+        }
+
+        match req.url().path() {
+            HEALTH_PATH => healthz(req).await,
+            READY_PATH => self.0.call(req).await,
+            _ => Ok(next.run(req).await),
+        }
+    }
+}
+
+/// Probe the liveness and readiness of the server listening on the address
+/// (for container healthchecks), and return whether it's ready.
+pub fn probe(address: &str) -> bool {
+    let mut address = match address.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+        Some(a) => a,
+        None => {
+            eprintln!("Cannot resolve {}", address);
+            return false;
+        }
+    };
+
+    // Servers listening on all interfaces can be reached through loopback.
+    if address.ip().is_unspecified() {
+        let ip = match address.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        };
+        address = SocketAddr::new(ip, address.port());
+    }
+
+    let agent = ureq::AgentBuilder::new().timeout(PROBE_TIMEOUT).build();
+    [HEALTH_PATH, READY_PATH].into_iter().all(|path| {
+        let url = format!("http://{}{}", address, path);
+        match agent.get(&url).call() {
+            Ok(_) => true,
+            Err(ureq::Error::Status(status, resp)) => {
+                let body = resp.into_string().unwrap_or_default();
+                eprintln!("{} responded with {}: {}", url, status, body);
+                false
+            }
+            Err(e) => {
+                eprintln!("Cannot reach {}: {}", url, e);
+                false
+            }
+        }
+    })
+}
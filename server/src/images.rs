@@ -0,0 +1,314 @@
+use crate::metrics;
+use crate::staticfile::{self, Responder, StaticFile};
+use async_std::fs;
+use async_std::task;
+use http_types::Url;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use ring::digest;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+/// Paths under which images can be resized.
+const RESIZE_PATH_PREFIXES: &[&str] = &["/assets/images/", "/private/"];
+/// Length of the digests (in bytes) naming the directories of the variants.
+const VARIANTS_DIR_BYTES: usize = 16;
+/// Length of the names of the directories from before they were named by digests.
+const LEGACY_VARIANTS_DIR_LEN: usize = 16;
+/// Allowed values for width and height (so that the cache can't be filled with arbitrary sizes).
+const ALLOWED_SIZES: &[u32] = &[32, 64, 128, 256, 320, 480, 640, 800, 960, 1280, 1600, 1920];
+
+/// How the image should fit into the requested box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fit {
+    /// Scale (preserving aspect ratio) to fit inside the box.
+    Contain,
+    /// Scale (preserving aspect ratio) to fill the box and crop the rest.
+    Cover,
+    /// Stretch to the exact size of the box.
+    Fill,
+}
+
+/// Parameters for resizing an image (from the query).
+#[derive(Debug, PartialEq)]
+struct ResizeParams {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    format: Option<ImageFormat>,
+}
+
+impl ResizeParams {
+    /// Parse the parameters from the query (if any), or return an error message.
+    fn from_url(url: &Url) -> Result<Option<Self>, String> {
+        let mut params = ResizeParams {
+            width: None,
+            height: None,
+            fit: Fit::Contain,
+            format: None,
+        };
+
+        let mut found = false;
+        for (key, value) in url.query_pairs() {
+            let size = || match value.parse::<u32>() {
+                Ok(s) if ALLOWED_SIZES.contains(&s) => Ok(Some(s)),
+                _ => Err(format!(
+                    "{} should be one of {:?}, not {:?}",
+                    key, ALLOWED_SIZES, value
+                )),
+            };
+
+            match &*key {
+                "w" => params.width = size()?,
+                "h" => params.height = size()?,
+                "fit" => {
+                    params.fit = match &*value {
+                        "contain" => Fit::Contain,
+                        "cover" => Fit::Cover,
+                        "fill" => Fit::Fill,
+                        _ => return Err(format!("unsupported fit {:?}", value)),
+                    }
+                }
+                "format" => {
+                    params.format = match &*value {
+                        "png" => Some(ImageFormat::Png),
+                        "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+                        "webp" => Some(ImageFormat::WebP),
+                        _ => return Err(format!("unsupported format {:?}", value)),
+                    }
+                }
+                _ => continue,
+            }
+
+            found = true;
+        }
+
+        if !found {
+            return Ok(None);
+        }
+
+        if params.fit != Fit::Contain && (params.width.is_none() || params.height.is_none()) {
+            return Err(String::from("fit needs both width and height"));
+        }
+
+        Ok(Some(params))
+    }
+
+    /// Key for this variant in the cache.
+    fn key(&self) -> String {
+        let size = |s: Option<u32>| s.map(|s| s.to_string()).unwrap_or_else(|| "auto".into());
+        format!("{}x{}-{:?}", size(self.width), size(self.height), self.fit).to_lowercase()
+    }
+
+    /// Resize the image (without upscaling).
+    fn apply(&self, img: DynamicImage) -> DynamicImage {
+        let (src_w, src_h) = (img.width(), img.height());
+        let (w, h) = match (self.width, self.height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, u32::MAX),
+            (None, Some(h)) => (u32::MAX, h),
+            (None, None) => return img,
+        };
+
+        let filter = FilterType::CatmullRom;
+        match self.fit {
+            Fit::Contain if w >= src_w && h >= src_h => img,
+            Fit::Contain => img.resize(w, h, filter),
+            Fit::Cover => img.resize_to_fill(w.min(src_w), h.min(src_h), filter),
+            Fit::Fill => img.resize_exact(w.min(src_w), h.min(src_h), filter),
+        }
+    }
+}
+
+/// Middleware for serving resized (and re-encoded) variants of images.
+///
+/// Variants are cached on disk, keyed by the path, the ETag of the source and the parameters.
+#[derive(Clone)]
+pub struct ImageResizer {
+    cache_root: PathBuf,
+}
+
+impl ImageResizer {
+    /// Create a resizer which caches the variants in the given directory.
+    pub fn new(cache_root: impl AsRef<Path>) -> Self {
+        let resizer = ImageResizer {
+            cache_root: PathBuf::from(cache_root.as_ref()),
+        };
+
+        resizer.remove_legacy_dirs();
+        resizer
+    }
+
+    /// Directory (relative to cache root) holding the variants of the source.
+    fn variants_dir(source: &Path) -> String {
+        // The digest has to be stable (across builds), or the variants would be orphaned.
+        let digest = digest::digest(&digest::SHA256, source.as_os_str().as_encoded_bytes());
+        digest.as_ref()[..VARIANTS_DIR_BYTES]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Remove the directories named by the (unstable) hashes used before, which
+    /// wouldn't be found (or cleaned up) anymore.
+    fn remove_legacy_dirs(&self) {
+        let entries = match std::fs::read_dir(&self.cache_root) {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let is_legacy = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+                n.len() == LEGACY_VARIANTS_DIR_LEN && n.chars().all(|c| c.is_ascii_hexdigit())
+            });
+            if is_legacy && path.is_dir() {
+                info!("Removing legacy variants in {}", path.display());
+                if let Err(e) = std::fs::remove_dir_all(&path) {
+                    error!("Cannot remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Remove the cached variants of the source (if any).
+    pub fn invalidate(&self, source: &Path) {
+        let dir = self.cache_root.join(Self::variants_dir(source));
+        if dir.exists() {
+            info!("Removing cached variants for {}", source.display());
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                error!("Cannot remove {}: {}", dir.display(), e);
+            }
+        }
+    }
+
+    /// Get the variant (relative to cache root) for the given image, creating it if needed.
+    async fn variant(
+        &self,
+        source: &Path,
+        in_format: ImageFormat,
+        params: ResizeParams,
+    ) -> io::Result<String> {
+        let meta = fs::metadata(source).await?;
+        let out_format = params.format.unwrap_or(match in_format {
+            ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP => in_format,
+            _ => ImageFormat::Png,
+        });
+
+        let rel_path = format!(
+            "{}/{}-{}.{}",
+            Self::variants_dir(source),
+            staticfile::etag(&meta)?,
+            params.key(),
+            out_format.extensions_str()[0]
+        );
+
+        let path = self.cache_root.join(&rel_path);
+        let cached = fs::metadata(&path).await.is_ok();
+        metrics::cache_lookup("images", cached);
+        if cached {
+            return Ok(rel_path);
+        }
+
+        let bytes = fs::read(source).await?;
+        let encoded = task::spawn_blocking(move || {
+            let img = image::load_from_memory_with_format(&bytes, in_format)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mut img = params.apply(img);
+            if out_format == ImageFormat::Jpeg {
+                // JPEG doesn't support alpha.
+                img = DynamicImage::ImageRgb8(img.to_rgb8());
+            }
+
+            let mut buf = Cursor::new(vec![]);
+            img.write_to(&mut buf, out_format)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok::<_, io::Error>(buf.into_inner())
+        })
+        .await?;
+
+        // Write and rename, so that concurrent requests never see partial files.
+        let parent = path.parent().expect("variant has a parent");
+        fs::create_dir_all(parent).await?;
+        let tmp_path = parent.join(format!(".{}", Uuid::new_v4().simple()));
+        fs::write(&tmp_path, encoded).await?;
+        fs::rename(&tmp_path, &path).await?;
+        info!("Cached variant {} for {}", path.display(), source.display());
+        Ok(rel_path)
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<StaticFile> for ImageResizer {
+    async fn handle(&self, req: Request<StaticFile>, next: Next<'_, StaticFile>) -> tide::Result {
+        let path = req.url().path();
+        if !RESIZE_PATH_PREFIXES.iter().any(|p| path.starts_with(p)) {
+            return Ok(next.run(req).await);
+        }
+
+        let params = match ResizeParams::from_url(req.url()) {
+            Ok(Some(p)) => p,
+            Ok(None) => return Ok(next.run(req).await),
+            Err(msg) => return Ok(Response::builder(StatusCode::BadRequest).body(msg).build()),
+        };
+
+        // Anything that's not a supported image is served as it is.
+        let source = req.state().get_path(path).ok().and_then(|p| {
+            ImageFormat::from_path(&p)
+                .ok()
+                .filter(|f| f.reading_enabled())
+                .map(|f| (p, f))
+        });
+
+        let (source, in_format) = match source {
+            Some(s) if s.0.is_file() => s,
+            _ => return Ok(next.run(req).await),
+        };
+
+        match self.variant(&source, in_format, params).await {
+            Ok(rel_path) => {
+                let state = req.state().with_root(&self.cache_root);
+                Ok(Responder::new(&req, &state, &rel_path).stream().await)
+            }
+            Err(e) => {
+                error!("Cannot resize {}: {}", source.display(), e);
+                Ok(next.run(req).await)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImageResizer;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_variants_dir_is_stable() {
+        // Changing this orphans all the cached variants.
+        assert_eq!(
+            ImageResizer::variants_dir(Path::new("/srv/public/assets/images/a.png")),
+            "1957c360dfdc2b024797127102bc5e24"
+        );
+    }
+
+    #[test]
+    fn test_legacy_dirs_are_removed() {
+        let root = std::env::temp_dir().join(format!("images-test-{}", std::process::id()));
+        let legacy = root.join("0123456789abcdef");
+        let current = root.join(ImageResizer::variants_dir(Path::new("/a.png")));
+        let other = root.join("notes");
+        for dir in [&legacy, &current, &other] {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        ImageResizer::new(&root);
+        assert!(!legacy.exists());
+        assert!(current.exists());
+        assert!(other.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
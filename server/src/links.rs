@@ -0,0 +1,335 @@
+use crate::auth;
+use crate::staticfile::{Lookup, StaticFile};
+use async_std::fs;
+use async_std::stream::StreamExt;
+use http_types::Url;
+use tide::{Body, Endpoint, Request, Response, StatusCode};
+
+use std::path::{Path, PathBuf};
+
+/// Base used for resolving relative links (only the path matters).
+const BASE_URL: &str = "http://localhost";
+/// Maximum number of redirects to follow for a single link.
+const MAX_REDIRECTS: usize = 5;
+/// Tags and their attributes which point to other resources.
+const LINK_ATTRS: &[(&str, &str)] = &[
+    ("a", "href"),
+    ("link", "href"),
+    ("area", "href"),
+    ("script", "src"),
+    ("img", "src"),
+    ("source", "src"),
+    ("video", "src"),
+    ("audio", "src"),
+    ("iframe", "src"),
+    ("embed", "src"),
+];
+
+/// A link (or asset) which doesn't resolve to anything.
+#[derive(Serialize)]
+pub struct BrokenLink {
+    /// Page containing the link.
+    page: String,
+    /// Link as it appears in the page.
+    target: String,
+    /// Tag which has the link.
+    tag: String,
+}
+
+/// Results of checking all the pages in a root.
+#[derive(Default, Serialize)]
+pub struct LinkReport {
+    pages: usize,
+    links: usize,
+    /// Dangling links in anchors.
+    broken_links: Vec<BrokenLink>,
+    /// Missing stylesheets, scripts, images, etc.
+    missing_assets: Vec<BrokenLink>,
+}
+
+impl LinkReport {
+    /// Whether all the links resolved.
+    pub fn is_ok(&self) -> bool {
+        self.broken_links.is_empty() && self.missing_assets.is_empty()
+    }
+}
+
+/// Checker for internal links in the HTML files of a root.
+#[derive(Clone)]
+pub struct LinkChecker {
+    state: StaticFile,
+    /// Paths (relative to root) to be skipped.
+    excluded: Vec<PathBuf>,
+}
+
+impl LinkChecker {
+    /// Create a checker which resolves links using the given handler.
+    pub fn new(state: &StaticFile, excluded: &[&str]) -> Self {
+        LinkChecker {
+            state: state.clone(),
+            excluded: excluded.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    /// Find all the HTML files in the root.
+    async fn pages(&self) -> Vec<PathBuf> {
+        let (root, mut pages) = (self.state.root(), vec![]);
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("Cannot read {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+
+            while let Some(Ok(entry)) = entries.next().await {
+                let path: PathBuf = entry.path().into();
+                let rel_path = path.strip_prefix(root).expect("walking inside root");
+                if self.excluded.iter().any(|p| rel_path.starts_with(p)) {
+                    continue;
+                }
+
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path
+                    .extension()
+                    .map(|e| e == "html" || e == "htm")
+                    .unwrap_or(false)
+                {
+                    pages.push(path);
+                }
+            }
+        }
+
+        pages.sort();
+        pages
+    }
+
+    /// Check whether the URL path resolves to a file (following redirects).
+    async fn resolves(&self, url: &Url) -> bool {
+        let mut url_path = url.path().to_owned();
+        for _ in 0..MAX_REDIRECTS {
+            let path = match self.state.get_path(&url_path) {
+                Ok(p) => p,
+                Err(_) => return false,
+            };
+
+            match self.state.lookup(path, &url_path).await {
+                Ok(Lookup::File(..)) => return true,
+                Ok(Lookup::Redirect(location)) => match url.join(&location) {
+                    // Redirects out of this site are assumed to be fine.
+                    Ok(u) if u.origin() != url.origin() => return true,
+                    Ok(u) => url_path = u.path().to_owned(),
+                    Err(_) => return false,
+                },
+                _ => return false,
+            }
+        }
+
+        false
+    }
+
+    /// Check all the pages and report the links which don't resolve.
+    pub async fn check(&self) -> LinkReport {
+        let mut report = LinkReport::default();
+        let root = self.state.root();
+        for page in self.pages().await {
+            let content = match fs::read(&page).await {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Err(e) => {
+                    warn!("Cannot read {}: {}", page.display(), e);
+                    continue;
+                }
+            };
+
+            let rel_path = page.strip_prefix(root).expect("page inside root");
+            let mut page_url = String::from("/") + &crate::util::encode_path(rel_path);
+            if page_url.ends_with("/index.html") {
+                page_url.truncate(page_url.len() - "index.html".len());
+            }
+
+            let base = Url::parse(BASE_URL)
+                .and_then(|u| u.join(&page_url))
+                .expect("page URL");
+            report.pages += 1;
+
+            for (tag, target) in extract_links(&content) {
+                let url = match base.join(&target) {
+                    Ok(u) if u.origin() == base.origin() => u,
+                    // External links (and invalid ones) are ignored.
+                    _ => continue,
+                };
+
+                report.links += 1;
+                if self.resolves(&url).await {
+                    continue;
+                }
+
+                let broken = BrokenLink {
+                    page: page_url.clone(),
+                    target,
+                    tag: tag.clone(),
+                };
+
+                if tag == "a" || tag == "area" {
+                    report.broken_links.push(broken);
+                } else {
+                    report.missing_assets.push(broken);
+                }
+            }
+        }
+
+        report
+    }
+}
+
+/// Iterate over the (lowercase) names and the contents of the tags in an HTML document.
+pub fn tags(html: &str) -> impl Iterator<Item = (String, &str)> {
+    let mut rest = html;
+    std::iter::from_fn(move || {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let tag = &rest[..end];
+        rest = &rest[end..];
+
+        let name = tag
+            .split(char::is_whitespace)
+            .next()
+            .unwrap_or("")
+            .trim_end_matches('/')
+            .to_lowercase();
+        Some((name, tag))
+    })
+}
+
+/// Whether the link points to something else in this site.
+pub fn is_internal(link: &str) -> bool {
+    // Fragments, and links to other schemes aren't checked.
+    !(link.is_empty()
+        || link.starts_with('#')
+        || link.starts_with("//")
+        || link
+            .split(['/', '?', '#'])
+            .next()
+            .map(|s| s.contains(':'))
+            .unwrap_or(false))
+}
+
+/// Extract the (tag, link) pairs from an HTML document.
+fn extract_links(html: &str) -> Vec<(String, String)> {
+    let mut links = vec![];
+    for (name, tag) in tags(html) {
+        let attr = match LINK_ATTRS.iter().find(|(t, _)| *t == name) {
+            Some((_, a)) => a,
+            None => continue,
+        };
+
+        if let Some(value) = attribute(tag, attr).map(str::trim) {
+            if is_internal(value) {
+                links.push((name, decode_entities(value)));
+            }
+        }
+    }
+
+    links
+}
+
+/// Find the value of the given attribute in the tag.
+pub fn attribute<'a>(tag: &'a str, attr: &str) -> Option<&'a str> {
+    // Only ASCII is lowercased, so that the offsets are the same as in the tag.
+    let lower = tag.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(i) = lower[offset..].find(attr) {
+        let start = offset + i;
+        offset = start + attr.len();
+        // The attribute should be preceded by whitespace (to avoid `data-src`, etc.)
+        if !tag[..start].ends_with(char::is_whitespace) {
+            continue;
+        }
+
+        let rest = tag[offset..].trim_start();
+        let rest = match rest.strip_prefix('=') {
+            Some(r) => r.trim_start(),
+            None => continue,
+        };
+
+        return match rest.chars().next() {
+            Some(q @ '"') | Some(q @ '\'') => rest[1..].split(q).next(),
+            Some(_) => rest.split(char::is_whitespace).next(),
+            None => None,
+        };
+    }
+
+    None
+}
+
+pub fn decode_entities(s: &str) -> String {
+    s.replace("&amp;", "&")
+}
+
+/// Run the checker for the given root and print the report.
+pub async fn run(root: impl AsRef<Path>, excluded: &[&str]) -> bool {
+    let checker = LinkChecker::new(&StaticFile::new(root), excluded);
+    let report = checker.check().await;
+    for link in &report.broken_links {
+        println!("{}: broken link {}", link.page, link.target);
+    }
+
+    for link in &report.missing_assets {
+        println!("{}: missing {} {}", link.page, link.tag, link.target);
+    }
+
+    println!(
+        "Checked {} links in {} pages: {} broken links, {} missing assets.",
+        report.links,
+        report.pages,
+        report.broken_links.len(),
+        report.missing_assets.len()
+    );
+    report.is_ok()
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for LinkChecker {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !auth::is_authorized(&req) {
+            return Ok(auth::unauthorized());
+        }
+
+        let mut resp = Response::new(StatusCode::Ok);
+        resp.set_body(Body::from_json(&self.check().await)?);
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::attribute;
+
+    #[test]
+    fn test_attribute() {
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            ("<a href=\"/a.html\">", "href", Some("/a.html")),
+            ("<a HREF='/a.html'>", "href", Some("/a.html")),
+            ("<a href = /a.html class=x>", "href", Some("/a.html")),
+            (
+                "<img data-src=\"/x.png\" src=\"/a.png\">",
+                "src",
+                Some("/a.png"),
+            ),
+            ("<img data-src=\"/x.png\">", "src", None),
+            ("<a href>", "href", None),
+            ("<a name=\"x\">", "href", None),
+            // Lowercasing these (outside ASCII) changes their lengths.
+            ("<img alt=\"İİİİ\" src=\"/a.png\">", "src", Some("/a.png")),
+            ("<a title='ȺȺȺ' HREF=\"/İ.html\">", "href", Some("/İ.html")),
+        ];
+
+        for (tag, attr, expected) in cases {
+            assert_eq!(attribute(tag, attr), *expected, "{} of {:?}", attr, tag);
+        }
+    }
+}
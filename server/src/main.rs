@@ -3,15 +3,78 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 #[macro_use]
+extern crate prometheus;
+#[macro_use]
 extern crate serde_derive;
 
+mod access;
+mod acl;
+mod acme;
+mod auth;
+mod bus;
+mod cidr;
+mod health;
+mod images;
+mod links;
+mod metrics;
+mod minify;
+mod missing;
+mod preload;
+mod protect;
+mod proxy;
+mod ratelimit;
+mod redirects;
+mod resolver;
+mod search;
+mod serve;
 mod server;
+mod settings;
+mod shutdown;
 mod sms;
 mod staticfile;
+mod tls;
+mod upload;
 mod util;
 mod watcher;
+mod webdav;
+
+use clap::Parser;
+use settings::{Cli, Command, Settings};
+
+use std::process;
 
 #[async_std::main]
 async fn main() {
-    server::start().await;
+    let cli = Cli::parse();
+    match Settings::load(&cli) {
+        Ok(s) => settings::init(s),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    }
+
+    match cli.command {
+        Some(Command::CheckLinks) => {
+            util::prepare_logger(&settings::get().log_level);
+            let ok = links::run(
+                &settings::get().source,
+                &[server::PRIVATE_PATH_PREFIX.trim_start_matches('/')],
+            )
+            .await;
+            process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::Healthcheck) => {
+            let ok = health::probe(&settings::get().address);
+            process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::HashPassword) => match protect::hash_password() {
+            Ok(hash) => println!("{}", hash),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => server::start().await,
+    }
 }
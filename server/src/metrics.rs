@@ -0,0 +1,160 @@
+use crate::acme::CHALLENGE_PATH_PREFIX;
+use crate::server::{
+    ADMIN_PATH_PREFIX, DAV_PATH_PREFIX, PRIVATE_PATH_PREFIX, SEARCH_PATH, UPLOAD_PATH_PREFIX,
+};
+use crate::util;
+use prometheus::{Encoder, Gauge, HistogramVec, IntCounterVec, IntGauge, TextEncoder};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of requests by mount and status class.",
+        &["mount", "status"]
+    )
+    .unwrap();
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time taken for sending the responses (including their bodies).",
+        &["mount", "status"]
+    )
+    .unwrap();
+    static ref RESPONSE_BYTES: IntCounterVec = register_int_counter_vec!(
+        "http_response_bytes_total",
+        "Bytes sent in the response bodies.",
+        &["mount"]
+    )
+    .unwrap();
+    static ref OPEN_CONNECTIONS: IntGauge =
+        register_int_gauge!("http_open_connections", "Number of open connections.").unwrap();
+    static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "cache_lookups_total",
+        "Lookups in the caches (`http` is for the conditional requests).",
+        &["cache", "result"]
+    )
+    .unwrap();
+    static ref PRIVATE_LINKS: IntGauge =
+        register_int_gauge!("private_links", "Number of private links being served.").unwrap();
+    static ref NEXT_EXPIRY: Gauge = register_gauge!(
+        "private_links_next_expiry_seconds",
+        "Time until the next private link expires."
+    )
+    .unwrap();
+    static ref WATCHER_DURATION: HistogramVec = register_histogram_vec!(
+        "watcher_duration_seconds",
+        "Time taken for reflecting the changes in the private root, and for copying the entries.",
+        &["operation"]
+    )
+    .unwrap();
+    static ref NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "notifications_total",
+        "Messages sent (or failed to be sent).",
+        &["result"]
+    )
+    .unwrap();
+    static ref PRIVATE_ACCESSES: IntCounterVec = register_int_counter_vec!(
+        "private_accesses_total",
+        "Accesses of the private entries.",
+        &["entry"]
+    )
+    .unwrap();
+}
+
+/// Mount (for the labels) of the given path.
+fn mount(path: &str) -> &'static str {
+    [
+        PRIVATE_PATH_PREFIX,
+        UPLOAD_PATH_PREFIX,
+        DAV_PATH_PREFIX,
+        SEARCH_PATH,
+        ADMIN_PATH_PREFIX,
+        CHALLENGE_PATH_PREFIX,
+    ]
+    .into_iter()
+    .find(|p| {
+        path.strip_prefix(p)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+    .unwrap_or("/")
+}
+
+/// Decrements the count of open connections when it's dropped.
+pub struct Connection(());
+
+impl Connection {
+    pub fn open() -> Self {
+        OPEN_CONNECTIONS.inc();
+        Connection(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.dec();
+    }
+}
+
+/// Record a lookup in the cache.
+pub fn cache_lookup(cache: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[cache, result]).inc();
+}
+
+/// Record the private links (and the time until the next one expires).
+pub fn set_private_links(count: usize, next_expiry: Option<Duration>) {
+    PRIVATE_LINKS.set(count as i64);
+    NEXT_EXPIRY.set(next_expiry.map(|d| d.as_secs_f64()).unwrap_or(0.0));
+}
+
+/// Record the time taken for an operation in the watcher.
+pub fn observe_watcher(operation: &str, duration: Duration) {
+    WATCHER_DURATION
+        .with_label_values(&[operation])
+        .observe(duration.as_secs_f64());
+}
+
+/// Record whether a message was sent.
+pub fn notification(sent: bool) {
+    let result = if sent { "success" } else { "failure" };
+    NOTIFICATIONS.with_label_values(&[result]).inc();
+}
+
+/// Record an access of the private entry.
+pub fn private_access(entry: &str) {
+    PRIVATE_ACCESSES.with_label_values(&[entry]).inc();
+}
+
+/// Middleware which records the requests (once their responses have been sent).
+pub struct Metrics;
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Metrics {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let mount = mount(req.url().path());
+        let mut resp = next.run(req).await;
+        let status = format!("{}xx", resp.status() as u16 / 100);
+        util::observe_body(&mut resp, move |bytes| {
+            REQUESTS.with_label_values(&[mount, &status]).inc();
+            REQUEST_DURATION
+                .with_label_values(&[mount, &status])
+                .observe(start.elapsed().as_secs_f64());
+            RESPONSE_BYTES.with_label_values(&[mount]).inc_by(bytes);
+        });
+
+        Ok(resp)
+    }
+}
+
+/// Endpoint for the metrics (in the Prometheus text format).
+pub async fn export<State>(_req: Request<State>) -> tide::Result {
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    encoder.encode(&prometheus::gather(), &mut buf)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(buf)
+        .content_type(encoder.format_type())
+        .build())
+}
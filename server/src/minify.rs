@@ -0,0 +1,293 @@
+use crate::metrics;
+use async_std::fs;
+use async_std::task;
+use mime::Mime;
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Header for getting the original (unminified) files, for debugging.
+pub const BYPASS_HEADER: &str = "X-No-Minify";
+/// Maximum size of the minified files held in memory.
+const CACHE_SIZE_LIMIT: usize = 32 << 20;
+/// Elements whose content should be left alone when minifying HTML.
+const RAW_ELEMENTS: &[&str] = &["pre", "textarea", "script", "style"];
+
+/// Kinds of files that can be minified.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Html,
+    Css,
+    Js,
+}
+
+impl Kind {
+    /// Get the kind for the MIME type (if it's supported).
+    pub fn from_mime(mime: &Mime) -> Option<Self> {
+        match (mime.type_(), mime.subtype().as_str()) {
+            (mime::TEXT, "html") => Some(Kind::Html),
+            (mime::TEXT, "css") => Some(Kind::Css),
+            (mime::APPLICATION, "javascript") | (mime::TEXT, "javascript") => Some(Kind::Js),
+            _ => None,
+        }
+    }
+
+    fn minify(self, source: &str) -> Result<String, &'static str> {
+        match self {
+            Kind::Html => Ok(minify_html(source)),
+            Kind::Css => minifier::css::minify(source).map(|m| m.to_string()),
+            Kind::Js => minifier::js::minify(source).map(|m| m.to_string()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Minified content for the paths, along with the ETag of the source.
+    entries: HashMap<PathBuf, (String, Arc<Vec<u8>>)>,
+    /// Paths in the order of insertion (for evicting).
+    order: VecDeque<PathBuf>,
+    size: usize,
+}
+
+impl Cache {
+    /// Remove the entries for the path (and anything inside it).
+    fn remove(&mut self, path: &Path) {
+        let size = &mut self.size;
+        self.entries.retain(|p, (_, bytes)| {
+            let keep = !p.starts_with(path);
+            if !keep {
+                *size -= bytes.len();
+            }
+
+            keep
+        });
+        self.order.retain(|p| !p.starts_with(path));
+    }
+
+    fn get(&self, path: &Path, etag: &str) -> Option<Arc<Vec<u8>>> {
+        self.entries
+            .get(path)
+            .filter(|(e, _)| e == etag)
+            .map(|(_, b)| b.clone())
+    }
+
+    fn insert(&mut self, path: PathBuf, etag: String, bytes: Arc<Vec<u8>>) {
+        self.size += bytes.len();
+        match self.entries.insert(path.clone(), (etag, bytes)) {
+            Some((_, old)) => self.size -= old.len(),
+            None => self.order.push_back(path),
+        }
+
+        while self.size > CACHE_SIZE_LIMIT {
+            let path = match self.order.pop_front() {
+                Some(p) => p,
+                None => break,
+            };
+
+            if let Some((_, old)) = self.entries.remove(&path) {
+                self.size -= old.len();
+            }
+        }
+    }
+}
+
+/// Minifier for HTML, CSS and JS files, which caches the results in memory.
+#[derive(Clone, Default)]
+pub struct Minifier {
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl Minifier {
+    /// Drop the cached content for the path (and anything inside it).
+    pub fn invalidate(&self, path: &Path) {
+        self.cache.lock().expect("cache lock").remove(path);
+    }
+
+    /// Get the minified content of the file (with the given ETag).
+    pub async fn minify(&self, path: &Path, etag: &str, kind: Kind) -> io::Result<Arc<Vec<u8>>> {
+        let cached = self.cache.lock().expect("cache lock").get(path, etag);
+        metrics::cache_lookup("minify", cached.is_some());
+        if let Some(bytes) = cached {
+            return Ok(bytes);
+        }
+
+        let source = fs::read_to_string(path).await?;
+        let minified = task::spawn_blocking(move || kind.minify(&source))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let bytes = Arc::new(minified.into_bytes());
+        self.cache.lock().expect("cache lock").insert(
+            path.to_path_buf(),
+            etag.to_owned(),
+            bytes.clone(),
+        );
+        Ok(bytes)
+    }
+}
+
+/// Minify HTML by removing comments and collapsing whitespace.
+///
+/// This is conservative: conditional comments and the content of `pre`, `textarea`,
+/// `script` and `style` elements are left as they are.
+fn minify_html(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut rest = source;
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--").filter(|c| !is_conditional(c)) {
+            match comment.find("-->") {
+                Some(i) => rest = &comment[i + 3..],
+                None => rest = "",
+            }
+            continue;
+        }
+
+        let raw = RAW_ELEMENTS.iter().find(|name| {
+            rest.get(1..name.len() + 1)
+                .map(|n| rest.starts_with('<') && n.eq_ignore_ascii_case(name))
+                .unwrap_or(false)
+                && rest[name.len() + 1..].starts_with(|c: char| c == '>' || c.is_whitespace())
+        });
+
+        if let Some(name) = raw {
+            let closing = format!("</{}", name);
+            let end = rest
+                .to_ascii_lowercase()
+                .find(&closing)
+                .unwrap_or(rest.len());
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            if !rest.is_empty() {
+                out.push_str(&rest[..closing.len()]);
+                rest = &rest[closing.len()..];
+            }
+            continue;
+        }
+
+        // Tags are copied as they are (attribute values may have meaningful whitespace).
+        let is_tag = rest.starts_with('<')
+            && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!');
+        if is_tag {
+            let end = tag_end(rest);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let c = rest.chars().next().expect("non-empty string");
+        if c.is_whitespace() {
+            let end = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            // Newlines are kept (rather than spaces), so that line numbers aren't totally lost.
+            if !out.ends_with(char::is_whitespace) {
+                out.push(if rest[..end].contains('\n') {
+                    '\n'
+                } else {
+                    ' '
+                });
+            }
+
+            rest = &rest[end..];
+            continue;
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out.trim().to_owned()
+}
+
+/// Check whether the rest of a comment (after `<!--`) is part of a conditional comment,
+/// including the `<!-->` and `<!--<![endif]-->` markers of the downlevel-revealed form.
+fn is_conditional(comment: &str) -> bool {
+    comment.starts_with('[') || comment.starts_with('>') || comment.starts_with("<![")
+}
+
+/// Find the end of the tag at the start of the string (skipping over quoted values).
+fn tag_end(s: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '>') => return i + 1,
+            (None, '<') => return i,
+            _ => (),
+        }
+    }
+
+    s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::minify_html;
+
+    #[test]
+    fn test_minify_html() {
+        let cases: &[(&str, &str)] = &[
+            // whitespace between text and tags
+            ("  <p>a   b</p>  ", "<p>a b</p>"),
+            ("<p>a\n\n  b</p>", "<p>a\nb</p>"),
+            (
+                "<ul>\n  <li>a</li>\n  <li>b</li>\n</ul>",
+                "<ul>\n<li>a</li>\n<li>b</li>\n</ul>",
+            ),
+            // comments
+            ("<p>a<!-- note -->b</p>", "<p>ab</p>"),
+            ("<p>a<!-- <b> -- > -->b</p>", "<p>ab</p>"),
+            ("<p>a</p><!-- unterminated <p>b</p>", "<p>a</p>"),
+            ("<!--", ""),
+            // conditional comments
+            (
+                "<!--[if IE]>  <p>old</p>  <![endif]-->",
+                "<!--[if IE]> <p>old</p> <![endif]-->",
+            ),
+            (
+                "<!--[if !IE]><!-->  <p>new</p>  <!--<![endif]-->",
+                "<!--[if !IE]><!--> <p>new</p> <!--<![endif]-->",
+            ),
+            // raw elements
+            (
+                "<pre>  a\n\n  b  </pre>  <p>c</p>",
+                "<pre>  a\n\n  b  </pre> <p>c</p>",
+            ),
+            ("<PRE>  a  </pre>  x", "<PRE>  a  </pre> x"),
+            (
+                "<pre class=\"x\">  a  </PRE>  x  y",
+                "<pre class=\"x\">  a  </PRE> x y",
+            ),
+            ("<textarea>  a  </textarea>", "<textarea>  a  </textarea>"),
+            (
+                "<script>if (a  <b) {  }  // <!-- x\n</script>",
+                "<script>if (a  <b) {  }  // <!-- x\n</script>",
+            ),
+            ("<style> p  { } </Style>  ", "<style> p  { } </Style>"),
+            ("<pre>  unterminated  ", "<pre>  unterminated"),
+            // elements which only start like raw ones
+            ("<prefix>  a  </prefix>", "<prefix> a </prefix>"),
+            ("<scripts>  a  </scripts>", "<scripts> a </scripts>"),
+            // attribute values are kept as they are
+            ("<p title=\"a   b\">  c  </p>", "<p title=\"a   b\"> c </p>"),
+            ("<p title='a  >  b'>  c</p>", "<p title='a  >  b'> c</p>"),
+            (
+                "<p title=\"it's  <x>\">c</p>",
+                "<p title=\"it's  <x>\">c</p>",
+            ),
+            // things which aren't tags
+            ("a  <  b", "a < b"),
+            ("1 <2  3", "1 <2 3"),
+            ("café  au  lait", "café au lait"),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(&minify_html(input), expected, "minifying {:?}", input);
+        }
+    }
+}
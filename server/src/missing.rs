@@ -0,0 +1,165 @@
+use crate::auth;
+use crate::sms;
+use chrono::offset::Utc;
+use chrono::DateTime;
+use http_types::Method;
+use tide::{Body, Endpoint, Request, Response, StatusCode};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Maximum number of paths tracked at any time.
+const MAX_PATHS: usize = 1000;
+/// Maximum number of referrers tracked for each path.
+const MAX_REFERRERS: usize = 10;
+/// Maximum length of the tracked paths and referrers.
+const MAX_LENGTH: usize = 512;
+const DEFAULT_LIMIT: usize = 50;
+
+#[derive(Clone, Serialize)]
+struct Entry {
+    path: String,
+    count: usize,
+    /// Hits since the last report.
+    #[serde(skip)]
+    unreported: usize,
+    referrers: HashMap<String, usize>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Insert the key into the map, evicting the least common key (if it's full).
+fn bump<'a, V>(
+    map: &'a mut HashMap<String, V>,
+    key: &str,
+    limit: usize,
+    count: impl Fn(&V) -> usize,
+    new: impl FnOnce() -> V,
+) -> &'a mut V {
+    if !map.contains_key(key) && map.len() >= limit {
+        let least = map
+            .iter()
+            .min_by_key(|(_, v)| count(v))
+            .map(|(k, _)| k.clone());
+        if let Some(k) = least {
+            map.remove(&k);
+        }
+    }
+
+    map.entry(key.to_owned()).or_insert_with(new)
+}
+
+/// Bounded counter of the paths which were responded with 404.
+///
+/// Once full, the least requested path makes way for the new one.
+#[derive(Clone, Default)]
+pub struct MissingPaths {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl MissingPaths {
+    /// Record a request for a missing path (and where it came from).
+    pub fn record(&self, path: &str, referrer: Option<&str>) {
+        let truncate = |s: &str| s.chars().take(MAX_LENGTH).collect::<String>();
+        let path = truncate(path);
+        let mut entries = self.entries.lock().expect("missing paths lock");
+        let entry = bump(
+            &mut entries,
+            &path,
+            MAX_PATHS,
+            |e| e.count,
+            || Entry {
+                path: path.clone(),
+                count: 0,
+                unreported: 0,
+                referrers: HashMap::new(),
+                last_seen: Utc::now(),
+            },
+        );
+
+        entry.count += 1;
+        entry.unreported += 1;
+        entry.last_seen = Utc::now();
+        if let Some(r) = referrer.filter(|r| !r.is_empty()) {
+            *bump(
+                &mut entry.referrers,
+                &truncate(r),
+                MAX_REFERRERS,
+                |c| *c,
+                || 0,
+            ) += 1;
+        }
+    }
+
+    /// Get the most requested paths.
+    fn top(&self, limit: usize) -> Vec<Entry> {
+        let mut entries: Vec<_> = self
+            .entries
+            .lock()
+            .expect("missing paths lock")
+            .values()
+            .cloned()
+            .collect();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+        entries.truncate(limit);
+        entries
+    }
+
+    /// Get the paths (and their counts) which were requested since the last call.
+    fn take_unreported(&self) -> Vec<(String, usize)> {
+        let mut entries = self.entries.lock().expect("missing paths lock");
+        let mut paths: Vec<_> = entries
+            .values_mut()
+            .filter(|e| e.unreported > 0)
+            .map(|e| (e.path.clone(), std::mem::take(&mut e.unreported)))
+            .collect();
+        paths.sort_by(|(_, a), (_, b)| b.cmp(a));
+        paths
+    }
+
+    /// Periodically send a message with the paths which were missing over the interval.
+    pub fn notify_every(&self, interval: Duration) {
+        let missing = self.clone();
+        async_std::task::spawn(async move {
+            loop {
+                async_std::task::sleep(interval).await;
+                let paths = missing.take_unreported();
+                if paths.is_empty() {
+                    continue;
+                }
+
+                let msg = sms::summarize(
+                    "Missing:",
+                    paths.into_iter().map(|(p, c)| format!("{}: {}", p, c)),
+                );
+                sms::send(&msg).await;
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for MissingPaths {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !auth::is_authorized(&req) {
+            return Ok(auth::unauthorized());
+        }
+
+        // Admins clear the list once they've fixed the links.
+        if req.method() == Method::Delete {
+            self.entries.lock().expect("missing paths lock").clear();
+            return Ok(Response::new(StatusCode::NoContent));
+        }
+
+        let limit = req
+            .url()
+            .query_pairs()
+            .find(|(k, _)| k == "limit")
+            .and_then(|(_, v)| v.parse().ok())
+            .unwrap_or(DEFAULT_LIMIT);
+
+        let mut resp = Response::new(StatusCode::Ok);
+        resp.set_body(Body::from_json(&self.top(limit))?);
+        Ok(resp)
+    }
+}
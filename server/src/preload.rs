@@ -0,0 +1,294 @@
+use crate::links;
+use crate::metrics;
+use async_std::fs;
+use http_types::Url;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Manifest key for the resources which should be preloaded for all pages.
+const ALL_PAGES: &str = "*";
+
+/// Discovered resources for the paths, along with the ETag of the page.
+type PreloadCache = HashMap<PathBuf, (String, Vec<Preload>)>;
+
+/// A resource which should be preloaded.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Preload {
+    href: String,
+    /// Destination of the resource (`style`, `script`, `font`, etc.)
+    #[serde(rename = "as")]
+    dest: String,
+    /// MIME type of the resource (if any).
+    #[serde(rename = "type", default)]
+    mime: Option<String>,
+}
+
+impl Preload {
+    /// Value of this resource in a `Link` header.
+    fn link(&self) -> String {
+        let href = self.href.replace(['<', '>'], "");
+        let mut link = format!("<{}>; rel=preload; as={}", href, self.dest);
+        if let Some(ty) = &self.mime {
+            link.push_str(&format!("; type=\"{}\"", ty.replace('"', "")));
+        }
+
+        // Fonts are always fetched in anonymous mode.
+        if self.dest == "font" {
+            link.push_str("; crossorigin");
+        }
+
+        link
+    }
+}
+
+/// Builder of `Link: rel=preload` headers for HTML pages.
+///
+/// The critical resources of a page can either be declared in a manifest (a JSON object
+/// mapping URL paths to resources, with `*` for those shared by all pages), or discovered
+/// from the `<link>` and `<script>` tags in the head of the page.
+///
+/// NOTE: hyper (1.6) only handles informational responses on the client side, and its
+/// server refuses to send a 1xx status (apart from `100 Continue`), so we can't send
+/// `103 Early Hints` ourselves. Proxies and CDNs can generate them from the headers though.
+#[derive(Clone, Default)]
+pub struct Preloader {
+    manifest: HashMap<String, Vec<Preload>>,
+    discover: bool,
+    cache: Arc<Mutex<PreloadCache>>,
+}
+
+impl Preloader {
+    /// Create a preloader from the manifest (if any), optionally discovering the resources.
+    pub fn new(manifest: Option<&Path>, discover: bool) -> io::Result<Self> {
+        let manifest = match manifest {
+            Some(path) => serde_json::from_slice(&std::fs::read(path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            None => HashMap::new(),
+        };
+
+        Ok(Preloader {
+            manifest,
+            discover,
+            ..Default::default()
+        })
+    }
+
+    /// Drop the discovered resources for the path (and anything inside it).
+    pub fn invalidate(&self, path: &Path) {
+        self.cache
+            .lock()
+            .expect("cache lock")
+            .retain(|p, _| !p.starts_with(path));
+    }
+
+    /// Get the `Link` header value for the page (if there's anything to preload).
+    pub async fn links(&self, url_path: &str, path: &Path, etag: &str) -> Option<String> {
+        let mut preloads = self.manifest.get(ALL_PAGES).cloned().unwrap_or_default();
+
+        match self.manifest.get(url_path) {
+            Some(p) => preloads.extend(p.iter().cloned()),
+            None if self.discover => match self.discovered(url_path, path, etag).await {
+                Ok(p) => preloads.extend(p),
+                Err(e) => warn!("Cannot discover preloads in {}: {}", path.display(), e),
+            },
+            None => (),
+        }
+
+        let mut links: Vec<String> = vec![];
+        for link in preloads.iter().map(Preload::link) {
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+
+        Some(links.join(", ")).filter(|l| !l.is_empty())
+    }
+
+    /// Get the resources from the page (or the cache).
+    async fn discovered(
+        &self,
+        url_path: &str,
+        path: &Path,
+        etag: &str,
+    ) -> io::Result<Vec<Preload>> {
+        let cached = self
+            .cache
+            .lock()
+            .expect("cache lock")
+            .get(path)
+            .filter(|(e, _)| e == etag)
+            .map(|(_, preloads)| preloads.clone());
+        metrics::cache_lookup("preload", cached.is_some());
+        if let Some(preloads) = cached {
+            return Ok(preloads);
+        }
+
+        let bytes = fs::read(path).await?;
+        let preloads = discover(&String::from_utf8_lossy(&bytes), url_path);
+        self.cache
+            .lock()
+            .expect("cache lock")
+            .insert(path.to_path_buf(), (etag.to_owned(), preloads.clone()));
+        Ok(preloads)
+    }
+}
+
+/// Find the stylesheets, scripts and preloads in the head of the page.
+fn discover(html: &str, url_path: &str) -> Vec<Preload> {
+    let base = match Url::parse("http://localhost").and_then(|u| u.join(url_path)) {
+        Ok(u) => u,
+        Err(_) => return vec![],
+    };
+
+    let mut preloads = vec![];
+    for (name, tag) in links::tags(html) {
+        let attr = |a| links::attribute(tag, a).map(|v| links::decode_entities(v.trim()));
+        let (href, dest, mime) = match &*name {
+            "/head" | "body" => break,
+            "script" => match attr("src") {
+                Some(src) => (src, String::from("script"), None),
+                None => continue,
+            },
+            "link" => {
+                let rel = attr("rel").unwrap_or_default().to_lowercase();
+                let href = match attr("href") {
+                    Some(h) => h,
+                    None => continue,
+                };
+
+                match rel
+                    .split_whitespace()
+                    .find(|r| *r == "stylesheet" || *r == "preload")
+                {
+                    Some("stylesheet") => (href, String::from("style"), None),
+                    Some(_) => match attr("as") {
+                        Some(dest) => (href, dest, attr("type")),
+                        None => continue,
+                    },
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+
+        if !links::is_internal(&href) {
+            continue;
+        }
+
+        // Normalize the links, so that they work regardless of where the header ends up.
+        let href = match base.join(&href) {
+            Ok(u) if u.origin() == base.origin() => {
+                let mut href = u.path().to_owned();
+                if let Some(q) = u.query() {
+                    href.push('?');
+                    href.push_str(q);
+                }
+
+                href
+            }
+            _ => continue,
+        };
+
+        preloads.push(Preload { href, dest, mime });
+    }
+
+    preloads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discover, Preload};
+
+    /// A page, the URL path it's served at, and the expected `(href, as, type)`.
+    type Case<'a> = (&'a str, &'a str, &'a [(&'a str, &'a str, Option<&'a str>)]);
+
+    #[test]
+    fn test_discover() {
+        let cases: &[Case] = &[
+            // relative URLs are resolved against the page
+            (
+                "<link rel=stylesheet href=a.css><script src=\"js/b.js\"></script>",
+                "/docs/page.html",
+                &[
+                    ("/docs/a.css", "style", None),
+                    ("/docs/js/b.js", "script", None),
+                ],
+            ),
+            (
+                "<link rel=stylesheet href=\"../a.css?v=1\">",
+                "/docs/guide/",
+                &[("/docs/a.css?v=1", "style", None)],
+            ),
+            (
+                "<link rel=stylesheet href=\"/a.css#x\"><script src=\"../../../b.js\">",
+                "/docs/page.html",
+                &[("/a.css", "style", None), ("/b.js", "script", None)],
+            ),
+            (
+                "<script src=\"a.js?x=1&amp;y=2\">",
+                "/",
+                &[("/a.js?x=1&y=2", "script", None)],
+            ),
+            // external links are skipped
+            (
+                "<link rel=stylesheet href=\"https://cdn.example.com/a.css\">\
+                 <script src=\"//cdn.example.com/b.js\"></script>\
+                 <script src=\"data:text/javascript,1\"></script>",
+                "/",
+                &[],
+            ),
+            // `rel` is a case-insensitive list
+            (
+                "<link REL=\"alternate Stylesheet\" href=a.css>",
+                "/",
+                &[("/a.css", "style", None)],
+            ),
+            (
+                "<link rel=\"icon\" href=a.png><link rel=canonical href=/>",
+                "/",
+                &[],
+            ),
+            // preloads need `as` (and keep their type)
+            (
+                "<link rel=preload href=a.woff2 as=font type=\"font/woff2\">\
+                 <link rel=preload href=b.png>",
+                "/",
+                &[("/a.woff2", "font", Some("font/woff2"))],
+            ),
+            // inline scripts and links without `href` are skipped
+            ("<script>var a = 1;</script><link rel=stylesheet>", "/", &[]),
+            // only the head is looked at
+            (
+                "<head><script src=a.js></script></head><script src=b.js></script>",
+                "/",
+                &[("/a.js", "script", None)],
+            ),
+            (
+                "<script src=a.js></script><body><script src=b.js></script>",
+                "/",
+                &[("/a.js", "script", None)],
+            ),
+        ];
+
+        for (html, url_path, expected) in cases {
+            let expected: Vec<Preload> = expected
+                .iter()
+                .map(|(href, dest, mime)| Preload {
+                    href: href.to_string(),
+                    dest: dest.to_string(),
+                    mime: mime.map(String::from),
+                })
+                .collect();
+            assert_eq!(
+                discover(html, url_path),
+                expected,
+                "discovering in {:?} at {}",
+                html,
+                url_path
+            );
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use crate::proxy;
+use crate::ratelimit::{self, Limiter};
+use crate::resolver;
+use crate::settings::{ProtectMode, ProtectSettings};
+use crate::util;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use async_std::task;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use http_types::auth::BasicAuth;
+use http_types::headers::{AUTHORIZATION, CACHE_CONTROL, LOCATION, WWW_AUTHENTICATE};
+use percent_encoding::NON_ALPHANUMERIC;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use tide::http::cookies::SameSite;
+use tide::sessions::{MemoryStore, SessionMiddleware};
+use tide::{Endpoint, Middleware, Next, Request, Response, StatusCode};
+
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const LOGIN_PATH: &str = "/_login";
+pub const LOGOUT_PATH: &str = "/_logout";
+
+const SESSION_COOKIE: &str = "waffles.sid";
+/// Time for which the verified Basic credentials are cached.
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+/// Number of verified Basic credentials which are cached.
+const MAX_VERIFIED: usize = 1000;
+/// Login forms are tiny, so anything bigger isn't one.
+const MAX_FORM_SIZE: usize = 8 << 10;
+
+const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>Log in to {realm}</title>
+</head>
+<body>
+<h1>Log in to {realm}</h1>
+<p>{message}</p>
+<form method="post" action="{action}">
+<input type="hidden" name="next" value="{next}">
+<p><label>Username <input name="username" autocomplete="username" required autofocus></label></p>
+<p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+<p><button type="submit">Log in</button></p>
+</form>
+</body>
+</html>
+"#;
+
+/// Paths protected by a set of credentials.
+struct Rule {
+    globs: GlobSet,
+    credentials: String,
+    mode: ProtectMode,
+}
+
+/// Basic credentials (the digests of the credential sets and the headers) which
+/// were verified recently, so that the clients don't need the password hashed
+/// for every request.
+#[derive(Default)]
+struct VerifiedCache(Mutex<HashMap<Vec<u8>, Instant>>);
+
+impl VerifiedCache {
+    fn key(credentials: &str, header: &str) -> Vec<u8> {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(credentials.as_bytes());
+        ctx.update(b"\0");
+        ctx.update(header.as_bytes());
+        ctx.finish().as_ref().to_vec()
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        let verified = self.0.lock().expect("verified lock");
+        verified
+            .get(key)
+            .is_some_and(|t| t.elapsed() < VERIFIED_TTL)
+    }
+
+    fn insert(&self, key: Vec<u8>) {
+        let mut verified = self.0.lock().expect("verified lock");
+        if verified.len() >= MAX_VERIFIED {
+            verified.retain(|_, t| t.elapsed() < VERIFIED_TTL);
+            if verified.len() >= MAX_VERIFIED {
+                verified.clear();
+            }
+        }
+
+        verified.insert(key, Instant::now());
+    }
+}
+
+/// Password protection of the public paths, which is shared by the middleware,
+/// the login page and the search (which shouldn't reveal the protected pages).
+#[derive(Clone)]
+pub struct Protection {
+    rules: Arc<Vec<Rule>>,
+    /// Credential sets, with the users mapped to their hashes.
+    credentials: Arc<HashMap<String, HashMap<String, String>>>,
+    /// Budget for the failed attempts of each client.
+    failures: Arc<Limiter>,
+    /// Basic credentials which were verified recently.
+    verified: Arc<VerifiedCache>,
+}
+
+impl Protection {
+    pub fn new(settings: &ProtectSettings) -> Self {
+        let rules = settings
+            .rules
+            .iter()
+            .map(|r| {
+                let mut globs = GlobSetBuilder::new();
+                for path in &r.paths {
+                    // Wildcards stay within their components (like gitignore).
+                    let glob = GlobBuilder::new(path)
+                        .literal_separator(true)
+                        .build()
+                        .expect("invalid glob");
+                    globs.add(glob);
+                }
+
+                Rule {
+                    globs: globs.build().expect("invalid globs"),
+                    credentials: r.credentials.clone(),
+                    mode: r.mode,
+                }
+            })
+            .collect();
+
+        Protection {
+            rules: Arc::new(rules),
+            credentials: Arc::new(settings.credentials.clone()),
+            failures: Arc::new(Limiter::new(
+                settings.failed_per_minute,
+                settings.failed_burst,
+            )),
+            verified: Arc::new(VerifiedCache::default()),
+        }
+    }
+
+    /// Whether any of the paths are protected with the login page.
+    pub fn has_form(&self) -> bool {
+        self.rules.iter().any(|r| r.mode == ProtectMode::Form)
+    }
+
+    /// Rule for the (percent-encoded) URL path, if it's protected.
+    fn rule(&self, path: &str) -> Option<&Rule> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        // Paths are matched the way they're resolved, so that they can't be
+        // encoded (or traversed) around the globs.
+        let path = resolver::resolve(Path::new("/"), path).ok()?;
+        // Everything under a matching directory is also protected (and the
+        // directories match the globs with or without the trailing slash).
+        let candidates = path
+            .ancestors()
+            .filter_map(|p| p.to_str())
+            .flat_map(|p| [p.to_owned(), format!("{}/", p.trim_end_matches('/'))])
+            .collect::<Vec<_>>();
+        self.rules
+            .iter()
+            .find(|r| candidates.iter().any(|c| r.globs.is_match(c)))
+    }
+
+    /// Whether the (percent-encoded) URL path is protected.
+    pub fn is_protected(&self, path: &str) -> bool {
+        self.rule(path).is_some()
+    }
+
+    /// Verify the password of the user in the credential set, where the failed
+    /// attempts are limited for each client.
+    async fn verify(
+        &self,
+        ip: Option<IpAddr>,
+        credentials: &str,
+        user: &str,
+        password: &str,
+    ) -> Result<bool, Duration> {
+        // Unknown clients share a budget.
+        let ip = ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        // Every attempt takes a token up front (so that concurrent attempts can't
+        // get more guesses than the budget), which is given back if it succeeds.
+        self.failures.check(ip)?;
+
+        let users = self.credentials.get(credentials);
+        let hash = users.and_then(|u| u.get(user));
+        // Unknown users are also hashed (with one of the other hashes), so that
+        // they take just as long as the wrong passwords.
+        let verified = match hash.or_else(|| users.and_then(|u| u.values().next())) {
+            Some(h) => {
+                let (h, password) = (h.clone(), password.to_owned());
+                task::spawn_blocking(move || {
+                    PasswordHash::new(&h).is_ok_and(|h| {
+                        Argon2::default()
+                            .verify_password(password.as_bytes(), &h)
+                            .is_ok()
+                    })
+                })
+                .await
+            }
+            None => false,
+        };
+
+        if verified && hash.is_some() {
+            self.failures.refund(ip);
+            return Ok(true);
+        }
+
+        warn!("Failed login of {:?} for {} from {}", user, credentials, ip);
+        Ok(false)
+    }
+
+    /// Middleware for the sessions of the login page.
+    pub fn sessions(ttl_hours: u64) -> SessionMiddleware<MemoryStore> {
+        // Sessions are in memory, so the secret doesn't have to outlive the server.
+        let mut secret = [0; 64];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .expect("cannot generate session secret");
+        SessionMiddleware::new(MemoryStore::new(), &secret)
+            .with_cookie_name(SESSION_COOKIE)
+            .with_same_site_policy(SameSite::Lax)
+            .with_session_ttl(Some(Duration::from_secs(ttl_hours * 3600)))
+            // Only the logins should set the cookies.
+            .without_save_unchanged()
+    }
+}
+
+/// Key of the user in the session for the credential set.
+fn session_key(credentials: &str) -> String {
+    format!("protect:{}", credentials)
+}
+
+/// Whether the path (from the client) can be redirected to after logging in.
+fn is_local(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
+fn basic_challenge(realm: &str) -> Response {
+    Response::builder(StatusCode::Unauthorized)
+        .header(
+            WWW_AUTHENTICATE,
+            format!(
+                "Basic realm=\"{}\", charset=\"UTF-8\"",
+                realm.replace('"', "")
+            ),
+        )
+        .build()
+}
+
+fn redirect(location: &str) -> Response {
+    Response::builder(StatusCode::SeeOther)
+        .header(LOCATION, location)
+        .build()
+}
+
+fn login_page(status: StatusCode, realm: &str, next: &str, message: &str) -> Response {
+    let page = LOGIN_PAGE
+        .replace("{action}", LOGIN_PATH)
+        .replace("{realm}", &util::escape_xml(realm))
+        .replace("{next}", &util::escape_xml(next))
+        .replace("{message}", &util::escape_xml(message));
+    Response::builder(status)
+        .header(CACHE_CONTROL, "no-store")
+        .content_type(tide::http::mime::HTML)
+        .body(page)
+        .build()
+}
+
+/// Shared caches shouldn't serve the protected responses to anyone else.
+fn protected(mut resp: Response) -> Response {
+    resp.insert_header(CACHE_CONTROL, "private");
+    resp
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Protection {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let rule = match self.rule(req.url().path()) {
+            Some(r) => r,
+            None => return Ok(next.run(req).await),
+        };
+
+        match rule.mode {
+            ProtectMode::Basic => {
+                let auth = match BasicAuth::from_headers(&req) {
+                    Ok(Some(a)) => a,
+                    _ => return Ok(basic_challenge(&rule.credentials)),
+                };
+
+                let header = req
+                    .header(AUTHORIZATION)
+                    .map(|h| h.as_str())
+                    .unwrap_or_default();
+                let key = VerifiedCache::key(&rule.credentials, header);
+                if self.verified.contains(&key) {
+                    return Ok(protected(next.run(req).await));
+                }
+
+                let ip = proxy::client_ip(&req);
+                match self
+                    .verify(ip, &rule.credentials, auth.username(), auth.password())
+                    .await
+                {
+                    Ok(true) => self.verified.insert(key),
+                    Ok(false) => return Ok(basic_challenge(&rule.credentials)),
+                    Err(retry_after) => return Ok(ratelimit::too_many_requests(retry_after)),
+                }
+            }
+            ProtectMode::Form => {
+                let key = session_key(&rule.credentials);
+                if req.session().get::<String>(&key).is_none() {
+                    let url = req.url();
+                    let next = match url.query() {
+                        Some(q) => format!("{}?{}", url.path(), q),
+                        None => url.path().to_owned(),
+                    };
+                    let next = percent_encoding::utf8_percent_encode(&next, NON_ALPHANUMERIC);
+                    return Ok(redirect(&format!("{}?next={}", LOGIN_PATH, next)));
+                }
+            }
+        }
+
+        Ok(protected(next.run(req).await))
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+    next: String,
+}
+
+/// Endpoint for the login page (`GET`) and for logging in (`POST`) to the paths
+/// protected with the login page.
+pub struct Login(pub Protection);
+
+impl Login {
+    /// Rule (with the login page) for the path to be redirected to after logging in.
+    fn rule(&self, next: &str) -> Option<&Rule> {
+        let path = next.split(['?', '#']).next().unwrap_or("");
+        self.0
+            .rule(path)
+            .filter(|r| r.mode == ProtectMode::Form && is_local(next))
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for Login {
+    async fn call(&self, mut req: Request<State>) -> tide::Result {
+        if req.method() != http_types::Method::Post {
+            let next = req
+                .url()
+                .query_pairs()
+                .find(|(k, _)| k == "next")
+                .map(|(_, v)| v.into_owned())
+                .unwrap_or_default();
+            return Ok(match self.rule(&next) {
+                Some(rule) => login_page(StatusCode::Ok, &rule.credentials, &next, ""),
+                None => Response::new(StatusCode::NotFound),
+            });
+        }
+
+        if req.len().is_none_or(|l| l > MAX_FORM_SIZE) {
+            return Ok(Response::new(StatusCode::PayloadTooLarge));
+        }
+
+        let form: LoginForm = match req.body_form().await {
+            Ok(f) => f,
+            Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+        };
+
+        let rule = match self.rule(&form.next) {
+            Some(r) => r,
+            None => return Ok(Response::new(StatusCode::BadRequest)),
+        };
+
+        let ip = proxy::client_ip(&req);
+        match self
+            .0
+            .verify(ip, &rule.credentials, &form.username, &form.password)
+            .await
+        {
+            Ok(true) => (),
+            Ok(false) => {
+                return Ok(login_page(
+                    StatusCode::Forbidden,
+                    &rule.credentials,
+                    &form.next,
+                    "Invalid username or password.",
+                ))
+            }
+            Err(retry_after) => return Ok(ratelimit::too_many_requests(retry_after)),
+        }
+
+        // The session shouldn't be the one which existed before logging in.
+        let session = req.session_mut();
+        session.regenerate();
+        session.insert(&session_key(&rule.credentials), &form.username)?;
+        Ok(redirect(&form.next))
+    }
+}
+
+/// Endpoint for logging out of all the paths protected with the login page.
+pub async fn logout<State>(mut req: Request<State>) -> tide::Result {
+    req.session_mut().destroy();
+    Ok(redirect("/"))
+}
+
+/// Hash the password (from stdin) for the credentials in the settings.
+pub fn hash_password() -> Result<String, String> {
+    let mut password = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| format!("Cannot read password: {}", e))?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(String::from("Password is empty"));
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| format!("Cannot hash password: {}", e))
+}
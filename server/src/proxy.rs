@@ -0,0 +1,496 @@
+use crate::cidr::Cidr;
+use http_types::url::Position;
+use http_types::Url;
+use tide::{Middleware, Next, Request};
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Client of a request, as seen through the trusted proxies (if any).
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub ip: IpAddr,
+    /// Scheme used by the client (`http` or `https`).
+    pub scheme: String,
+    /// Host requested by the client.
+    pub host: Option<String>,
+}
+
+/// Get the IP address of the client (or of the peer, if it's not known).
+pub fn client_ip<State>(req: &Request<State>) -> Option<IpAddr> {
+    req.ext::<Client>()
+        .map(|c| c.ip)
+        .or_else(|| peer_ip(req.peer_addr()))
+}
+
+fn peer_ip(addr: Option<&str>) -> Option<IpAddr> {
+    addr.and_then(|a| a.parse::<SocketAddr>().ok())
+        .map(|a| a.ip())
+}
+
+/// Host (and port, if any) of the URL.
+fn url_host(url: &Url) -> Option<String> {
+    url.host_str().map(|h| match url.port() {
+        Some(p) => format!("{}:{}", h, p),
+        None => h.to_owned(),
+    })
+}
+
+/// Parse a node (`1.2.3.4`, `1.2.3.4:80`, `[::1]:80`, `::1` or `"[::1]"`) from
+/// the forwarding headers.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+
+    node.parse().ok().or_else(|| match node.split_once(':') {
+        Some((ip, _port)) => ip.parse().ok(),
+        None => None,
+    })
+}
+
+/// Split the header value on the separator, except within the quoted strings.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Value of a parameter, which can be a token or a quoted string.
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                unquoted.push(match c {
+                    '\\' => chars.next().unwrap_or(c),
+                    c => c,
+                });
+            }
+
+            unquoted
+        }
+        None => value.to_owned(),
+    }
+}
+
+/// Parameters of the elements in a `Forwarded` header (RFC 7239).
+fn parse_forwarded(value: &str) -> Vec<Vec<(String, String)>> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .map(|element| {
+            split_unquoted(element, ';')
+                .into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(k, v)| (k.trim().to_lowercase(), unquote(v)))
+                .collect()
+        })
+        .collect()
+}
+
+/// Middleware which derives the client (IP, scheme and host) from the forwarding
+/// headers, but only when the request comes from one of the trusted proxies.
+pub struct TrustedProxies {
+    proxies: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(proxies: &[Cidr]) -> Self {
+        TrustedProxies {
+            proxies: proxies.to_vec(),
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.proxies.iter().any(|c| c.contains(ip))
+    }
+
+    fn client(&self, req: &http_types::Request) -> Option<Client> {
+        let peer = peer_ip(req.peer_addr())?;
+        // `Request::host` follows the forwarding headers even for untrusted peers.
+        let url = req.url();
+        let mut client = Client {
+            ip: peer,
+            scheme: url.scheme().to_owned(),
+            host: url_host(url),
+        };
+
+        if !self.is_trusted(&peer) {
+            return Some(client);
+        }
+
+        let header = |name| {
+            req.header(name).map(|values| {
+                values
+                    .iter()
+                    .map(|v| v.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+        };
+
+        // Each proxy appends the address it got the request from, so the client is
+        // the nearest address (from the end) which isn't one of our proxies.
+        let forwarded = header("Forwarded").map(|v| parse_forwarded(&v));
+        let chain = match &forwarded {
+            Some(elements) => elements
+                .iter()
+                .map(|e| {
+                    e.iter()
+                        .find(|(k, _)| k == "for")
+                        .and_then(|(_, v)| parse_node(v))
+                })
+                .collect(),
+            None => match header("X-Forwarded-For") {
+                Some(v) => v.split(',').map(parse_node).collect(),
+                None => header("X-Real-IP")
+                    .map(|v| vec![parse_node(&v)])
+                    .unwrap_or_default(),
+            },
+        };
+
+        for ip in chain.into_iter().rev() {
+            match ip {
+                Some(ip) => client.ip = ip,
+                // Unknown (or obfuscated) nodes can't be followed any further.
+                None => break,
+            }
+
+            if !self.is_trusted(&client.ip) {
+                break;
+            }
+        }
+
+        // Scheme and host are taken from the nearest proxy, since the others
+        // could've been sent by the client.
+        let last = |name: &str| {
+            forwarded
+                .as_ref()
+                .and_then(|elements| elements.last())
+                .and_then(|e| e.iter().find(|(k, _)| k == name))
+                .map(|(_, v)| v.clone())
+        };
+        let proto = last("proto").or_else(|| {
+            header("X-Forwarded-Proto")
+                .and_then(|v| v.rsplit(',').next().map(|s| s.trim().to_owned()))
+        });
+        let host = last("host").or_else(|| {
+            header("X-Forwarded-Host")
+                .and_then(|v| v.rsplit(',').next().map(|s| s.trim().to_owned()))
+        });
+
+        if let Some(p) = proto.map(|p| p.to_lowercase()) {
+            if p == "http" || p == "https" {
+                client.scheme = p;
+            }
+        }
+
+        let valid = |h: &String| !h.is_empty() && !h.contains(['/', '?', '#', '@', '\\']);
+        if let Some(h) = host.filter(valid) {
+            client.host = Some(h);
+        }
+
+        Some(client)
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TrustedProxies {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Some(client) = self.client(req.as_ref()) {
+            // Links built from the URL should point to what the client requested.
+            let url = req.url();
+            if url.scheme() != client.scheme || url_host(url) != client.host {
+                let host = client.host.as_deref().unwrap_or("");
+                let path = &url[Position::BeforePath..];
+                match Url::parse(&format!("{}://{}{}", client.scheme, host, path)) {
+                    Ok(u) => {
+                        let r: &mut http_types::Request = req.as_mut();
+                        *r.url_mut() = u;
+                    }
+                    Err(e) => debug!("Ignoring forwarded host {:?}: {}", host, e),
+                }
+            }
+
+            req.set_ext(client);
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_forwarded, parse_node, TrustedProxies};
+    use crate::cidr::Cidr;
+    use http_types::{Method, Request, Url};
+    use std::net::IpAddr;
+
+    type Headers = &'static [(&'static str, &'static str)];
+
+    fn proxies() -> TrustedProxies {
+        let proxies = ["10.0.0.0/8", "fd00::/8"]
+            .iter()
+            .map(|c| c.parse::<Cidr>().unwrap())
+            .collect::<Vec<_>>();
+        TrustedProxies::new(&proxies)
+    }
+
+    fn request(peer: &str, headers: &[(&str, &str)]) -> Request {
+        let mut req = Request::new(Method::Get, Url::parse("http://origin.test/a").unwrap());
+        req.set_peer_addr(Some(peer));
+        for (name, value) in headers {
+            req.append_header(*name, *value);
+        }
+
+        req
+    }
+
+    #[test]
+    fn test_parse_node() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("1.2.3.4", Some("1.2.3.4")),
+            (" 1.2.3.4 ", Some("1.2.3.4")),
+            ("1.2.3.4:80", Some("1.2.3.4")),
+            ("2001:db8::1", Some("2001:db8::1")),
+            ("[2001:db8::1]", Some("2001:db8::1")),
+            ("[2001:db8::1]:80", Some("2001:db8::1")),
+            ("\"[2001:db8::1]:80\"", Some("2001:db8::1")),
+            ("unknown", None),
+            ("_hidden", None),
+            ("[2001:db8::1", None),
+            ("", None),
+        ];
+
+        for (node, expected) in cases {
+            let expected = expected.map(|ip| ip.parse::<IpAddr>().unwrap());
+            assert_eq!(parse_node(node), expected, "parsing {:?}", node);
+        }
+    }
+
+    #[test]
+    fn test_parse_forwarded() {
+        let cases: &[(&str, &[Headers])] = &[
+            ("for=1.2.3.4", &[&[("for", "1.2.3.4")]]),
+            (
+                "For=1.2.3.4;Proto=https, for=5.6.7.8",
+                &[
+                    &[("for", "1.2.3.4"), ("proto", "https")],
+                    &[("for", "5.6.7.8")],
+                ],
+            ),
+            (
+                "for=\"[2001:db8::1]:4711\";host=\"example.com\"",
+                &[&[("for", "[2001:db8::1]:4711"), ("host", "example.com")]],
+            ),
+            // separators and escapes within the quoted strings
+            (
+                "for=1.2.3.4;x=\"a, for=6.6.6.6; y=z\"",
+                &[&[("for", "1.2.3.4"), ("x", "a, for=6.6.6.6; y=z")]],
+            ),
+            (
+                "x=\"a\\\"b, c\";for=1.2.3.4",
+                &[&[("x", "a\"b, c"), ("for", "1.2.3.4")]],
+            ),
+        ];
+
+        for (value, expected) in cases {
+            let expected = expected
+                .iter()
+                .map(|e| {
+                    e.iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(parse_forwarded(value), expected, "parsing {:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let cases: &[(&str, Headers, &str)] = &[
+            // untrusted peers can't forward
+            ("1.2.3.4:80", &[("X-Forwarded-For", "5.6.7.8")], "1.2.3.4"),
+            ("1.2.3.4:80", &[("Forwarded", "for=5.6.7.8")], "1.2.3.4"),
+            ("1.2.3.4:80", &[("X-Real-IP", "5.6.7.8")], "1.2.3.4"),
+            // trusted peers without any headers
+            ("10.0.0.1:80", &[], "10.0.0.1"),
+            ("10.0.0.1:80", &[("X-Forwarded-For", "5.6.7.8")], "5.6.7.8"),
+            ("10.0.0.1:80", &[("X-Real-IP", "5.6.7.8")], "5.6.7.8"),
+            // spoofed addresses before the nearest untrusted one are ignored
+            (
+                "10.0.0.1:80",
+                &[("X-Forwarded-For", "6.6.6.6, 5.6.7.8")],
+                "5.6.7.8",
+            ),
+            (
+                "10.0.0.1:80",
+                &[
+                    ("X-Forwarded-For", "6.6.6.6"),
+                    ("X-Forwarded-For", "5.6.7.8"),
+                ],
+                "5.6.7.8",
+            ),
+            (
+                "10.0.0.1:80",
+                &[("X-Forwarded-For", "6.6.6.6, 5.6.7.8, 10.0.0.2")],
+                "5.6.7.8",
+            ),
+            (
+                "10.0.0.1:80",
+                &[("X-Forwarded-For", "10.0.0.3, 10.0.0.2")],
+                "10.0.0.3",
+            ),
+            // unknown nodes can't be followed
+            (
+                "10.0.0.1:80",
+                &[("X-Forwarded-For", "5.6.7.8, unknown")],
+                "10.0.0.1",
+            ),
+            (
+                "10.0.0.1:80",
+                &[("Forwarded", "for=5.6.7.8, for=_hidden")],
+                "10.0.0.1",
+            ),
+            // IPv6 (and dual-stack) peers and nodes
+            (
+                "[fd00::1]:80",
+                &[("X-Forwarded-For", "[2001:db8::1]:4711")],
+                "2001:db8::1",
+            ),
+            (
+                "[fd00::1]:80",
+                &[("X-Forwarded-For", "2001:db8::1")],
+                "2001:db8::1",
+            ),
+            (
+                "[::ffff:10.0.0.1]:80",
+                &[("Forwarded", "for=\"[2001:db8::1]:4711\"")],
+                "2001:db8::1",
+            ),
+            (
+                "[2001:db8::2]:80",
+                &[("X-Forwarded-For", "5.6.7.8")],
+                "2001:db8::2",
+            ),
+            // Forwarded takes precedence over the others
+            (
+                "10.0.0.1:80",
+                &[
+                    ("Forwarded", "for=5.6.7.8"),
+                    ("X-Forwarded-For", "6.6.6.6"),
+                    ("X-Real-IP", "6.6.6.7"),
+                ],
+                "5.6.7.8",
+            ),
+            (
+                "10.0.0.1:80",
+                &[("X-Forwarded-For", "5.6.7.8"), ("X-Real-IP", "6.6.6.6")],
+                "5.6.7.8",
+            ),
+            // quoted separators aren't elements
+            (
+                "10.0.0.1:80",
+                &[("Forwarded", "for=5.6.7.8;x=\"y, for=6.6.6.6\"")],
+                "5.6.7.8",
+            ),
+        ];
+
+        let proxies = proxies();
+        for (peer, headers, expected) in cases {
+            let client = proxies.client(&request(peer, headers)).unwrap();
+            assert_eq!(
+                client.ip,
+                expected.parse::<IpAddr>().unwrap(),
+                "client of {} with {:?}",
+                peer,
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn test_client_scheme_and_host() {
+        let cases: &[(&str, Headers, &str, &str)] = &[
+            (
+                "1.2.3.4:80",
+                &[
+                    ("X-Forwarded-Proto", "https"),
+                    ("X-Forwarded-Host", "example.com"),
+                ],
+                "http",
+                "origin.test",
+            ),
+            (
+                "10.0.0.1:80",
+                &[
+                    ("X-Forwarded-Proto", "https"),
+                    ("X-Forwarded-Host", "example.com"),
+                ],
+                "https",
+                "example.com",
+            ),
+            // the nearest proxy is trusted
+            (
+                "10.0.0.1:80",
+                &[
+                    ("X-Forwarded-Proto", "gopher, https"),
+                    ("X-Forwarded-Host", "evil.test, example.com:8443"),
+                ],
+                "https",
+                "example.com:8443",
+            ),
+            (
+                "10.0.0.1:80",
+                &[
+                    (
+                        "Forwarded",
+                        "proto=http;host=evil.test, proto=https;host=\"example.com\"",
+                    ),
+                    ("X-Forwarded-Host", "other.test"),
+                ],
+                "https",
+                "example.com",
+            ),
+            // invalid values are ignored
+            (
+                "10.0.0.1:80",
+                &[
+                    ("X-Forwarded-Proto", "gopher"),
+                    ("X-Forwarded-Host", "evil.test/path"),
+                ],
+                "http",
+                "origin.test",
+            ),
+        ];
+
+        let proxies = proxies();
+        for (peer, headers, scheme, host) in cases {
+            let client = proxies.client(&request(peer, headers)).unwrap();
+            assert_eq!(
+                (client.scheme.as_str(), client.host.as_deref()),
+                (*scheme, Some(*host)),
+                "client of {} with {:?}",
+                peer,
+                headers
+            );
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use crate::proxy;
+use crate::server;
+use crate::settings::RateLimitSettings;
+use crate::watcher::PrivateLinks;
+use http_types::headers::RETRY_AFTER;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of clients tracked by a limiter, beyond which the idle ones are dropped.
+const MAX_CLIENTS: usize = 10_000;
+/// Number of clients kept once the limiter is full (so that it's pruned only once
+/// in a while, rather than for every new client).
+const PRUNE_TO: usize = MAX_CLIENTS * 9 / 10;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Client for the buckets, where the IPv6 clients are identified by their /64
+/// (which is usually assigned to a single host or network).
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let mask = !0u128 << 64;
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Token bucket for each client IP, which refills at the given rate up to the burst.
+pub struct Limiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(per_minute: f64, burst: u32) -> Self {
+        Limiter {
+            per_second: per_minute / 60.0,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for the client, or get the time after which it can retry.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.acquire(ip, Instant::now())
+    }
+
+    /// Give back the token taken for the client (when it shouldn't have counted).
+    pub fn refund(&self, ip: IpAddr) {
+        let mut buckets = self.buckets.lock().expect("buckets lock");
+        if let Some(bucket) = buckets.get_mut(&client_key(ip)) {
+            bucket.tokens = (bucket.tokens + 1.0).min(self.burst);
+        }
+    }
+
+    fn acquire(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let key = client_key(ip);
+        let mut buckets = self.buckets.lock().expect("buckets lock");
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&key) {
+            self.prune(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.per_second,
+        ))
+    }
+
+    /// Drop the full buckets (which are the same as new ones), and then the ones
+    /// which were updated the longest ago, until there's room for the new clients.
+    fn prune(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        let (per_second, burst) = (self.per_second, self.burst);
+        buckets.retain(|_, b| {
+            b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * per_second < burst
+        });
+        if buckets.len() <= PRUNE_TO {
+            return;
+        }
+
+        let mut updates = buckets.values().map(|b| b.updated).collect::<Vec<_>>();
+        let excess = buckets.len() - PRUNE_TO;
+        let (_, oldest_kept, _) = updates.select_nth_unstable(excess);
+        let oldest_kept = *oldest_kept;
+        buckets.retain(|_, b| b.updated >= oldest_kept);
+    }
+}
+
+/// Response for the clients which have run out of their budget.
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    Response::builder(StatusCode::TooManyRequests)
+        .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+        .body("Too many requests")
+        .build()
+}
+
+/// Middleware which limits the requests from each client IP, with budgets for
+/// the path prefixes, and a separate (stricter) one for the requests with
+/// invalid or expired private tokens.
+pub struct RateLimiter {
+    /// Limiters for the prefixes (longest first).
+    rules: Vec<(String, Limiter)>,
+    invalid_token: Limiter,
+    links: PrivateLinks,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings, links: PrivateLinks) -> Self {
+        let mut rules = settings
+            .rules
+            .iter()
+            .map(|r| (r.prefix.clone(), Limiter::new(r.per_minute, r.burst)))
+            .collect::<Vec<_>>();
+        rules.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        RateLimiter {
+            rules,
+            invalid_token: Limiter::new(
+                settings.invalid_token_per_minute,
+                settings.invalid_token_burst,
+            ),
+            links,
+        }
+    }
+
+    /// Limiter of the longest prefix which covers the (served) path (if any).
+    fn limiter(&self, path: &str) -> Option<&Limiter> {
+        self.rules
+            .iter()
+            .find(|(prefix, _)| server::has_path_prefix(path, prefix))
+            .map(|(_, l)| l)
+    }
+
+    /// Whether the (served) path has a private token which isn't being served currently.
+    fn has_invalid_token(&self, path: &str) -> bool {
+        let token = match server::private_token(path) {
+            Some(t) => t,
+            None => return false,
+        };
+
+        match token.parse::<Uuid>() {
+            Ok(id) => !self
+                .links
+                .read()
+                .expect("links lock poisoned")
+                .values()
+                .any(|l| *l == id),
+            Err(_) => true,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimiter {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let ip = match proxy::client_ip(&req) {
+            Some(ip) => ip,
+            None => return Ok(next.run(req).await),
+        };
+
+        let path = server::served_path(&req);
+        let mut limiters = self.limiter(&path).into_iter().collect::<Vec<_>>();
+        if self.has_invalid_token(&path) {
+            limiters.push(&self.invalid_token);
+        }
+
+        for limiter in limiters {
+            if let Err(retry_after) = limiter.check(ip) {
+                debug!("Rate limited {} for {}", ip, path);
+                return Ok(too_many_requests(retry_after));
+            }
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{too_many_requests, Limiter, RateLimiter, MAX_CLIENTS};
+    use crate::settings::{RateLimitRule, RateLimitSettings};
+    use http_types::headers::RETRY_AFTER;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::time::{Duration, Instant};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn test_burst() {
+        let limiter = Limiter::new(60.0, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        }
+
+        assert!(limiter.acquire(CLIENT, now).is_err());
+        // Other clients have their own buckets.
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(limiter.acquire(other, now), Ok(()));
+    }
+
+    #[test]
+    fn test_refill() {
+        // A token every 2 seconds.
+        let limiter = Limiter::new(30.0, 2);
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        assert!(limiter.acquire(CLIENT, now).is_err());
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(limiter.acquire(CLIENT, later), Ok(()));
+        assert!(limiter.acquire(CLIENT, later).is_err());
+
+        // Buckets don't fill beyond the burst.
+        let much_later = later + Duration::from_secs(3600);
+        assert_eq!(limiter.acquire(CLIENT, much_later), Ok(()));
+        assert_eq!(limiter.acquire(CLIENT, much_later), Ok(()));
+        assert!(limiter.acquire(CLIENT, much_later).is_err());
+    }
+
+    #[test]
+    fn test_refund() {
+        let limiter = Limiter::new(60.0, 1);
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        limiter.refund(CLIENT);
+        assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        assert!(limiter.acquire(CLIENT, now).is_err());
+
+        // Refunds don't fill beyond the burst.
+        limiter.refund(CLIENT);
+        limiter.refund(CLIENT);
+        assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        assert!(limiter.acquire(CLIENT, now).is_err());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        // A token every 6 seconds.
+        let limiter = Limiter::new(10.0, 1);
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        let wait = limiter.acquire(CLIENT, now).unwrap_err();
+        assert!((wait.as_secs_f64() - 6.0).abs() < 1e-6, "{:?}", wait);
+
+        let wait = limiter
+            .acquire(CLIENT, now + Duration::from_millis(4500))
+            .unwrap_err();
+        assert!((wait.as_secs_f64() - 1.5).abs() < 1e-6, "{:?}", wait);
+
+        // Partial seconds are rounded up.
+        let resp = too_many_requests(wait);
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp[RETRY_AFTER], "2");
+    }
+
+    #[test]
+    fn test_ipv6_clients_by_prefix() {
+        let limiter = Limiter::new(60.0, 1);
+        let now = Instant::now();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(limiter.acquire(ip("2001:db8:1:2::1"), now), Ok(()));
+        // Same /64
+        assert!(limiter.acquire(ip("2001:db8:1:2:ffff::7"), now).is_err());
+        // Different /64
+        assert_eq!(limiter.acquire(ip("2001:db8:1:3::1"), now), Ok(()));
+        // IPv4-mapped addresses are the IPv4 clients.
+        assert_eq!(limiter.acquire(CLIENT, now), Ok(()));
+        assert!(limiter.acquire(ip("::ffff:192.0.2.1"), now).is_err());
+    }
+
+    #[test]
+    fn test_clients_are_capped() {
+        let limiter = Limiter::new(60.0, 5);
+        let now = Instant::now();
+        for i in 0..(MAX_CLIENTS as u128 * 2) {
+            let ip = IpAddr::V6(Ipv6Addr::from(i << 64));
+            let at = now + Duration::from_millis(i as u64);
+            assert_eq!(limiter.acquire(ip, at), Ok(()));
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_CLIENTS);
+        }
+
+        // The most recent clients are kept.
+        let last = IpAddr::V6(Ipv6Addr::from((MAX_CLIENTS as u128 * 2 - 1) << 64));
+        let bucket = &limiter.buckets.lock().unwrap()[&last];
+        assert_eq!(bucket.tokens, 4.0);
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let rule = |prefix: &str, burst| RateLimitRule {
+            prefix: prefix.into(),
+            per_minute: 60.0,
+            burst,
+        };
+        let settings = RateLimitSettings {
+            rules: vec![rule("/api", 1), rule("/api/uploads/", 2)],
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(&settings, Default::default());
+
+        // Limiters are told apart by their bursts.
+        let cases: &[(&str, Option<u32>)] = &[
+            ("/api", Some(1)),
+            ("/api/", Some(1)),
+            ("/api/users", Some(1)),
+            ("/api/uploads", Some(2)),
+            ("/api/uploads/a.png", Some(2)),
+            ("/api/uploadsx", Some(1)),
+            ("/apiary", None),
+            ("/", None),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(
+                limiter.limiter(path).map(|l| l.burst as u32),
+                *expected,
+                "limiter for {:?}",
+                path
+            );
+        }
+    }
+}
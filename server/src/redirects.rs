@@ -0,0 +1,243 @@
+use crate::auth;
+use crate::bus::{Change, ChangeBus};
+use crate::staticfile::StaticFile;
+use crate::util;
+use crate::watcher;
+use http_types::headers::LOCATION;
+use http_types::Method;
+use tide::{Body, Endpoint, Middleware, Next, Request, Response, StatusCode};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Persistent table of redirects for the public files which have been renamed.
+///
+/// The old paths are stored decoded (for matching against the requests), while
+/// the new paths are stored as URLs (for the `Location` header).
+#[derive(Clone)]
+pub struct RedirectTable {
+    root: PathBuf,
+    /// Paths (relative to root) to be skipped.
+    excluded: Vec<PathBuf>,
+    file: PathBuf,
+    redirects: Arc<RwLock<BTreeMap<String, String>>>,
+}
+
+impl RedirectTable {
+    /// Load the table from the given file (if it exists) for the given root.
+    pub fn load(root: impl AsRef<Path>, excluded: &[&str], file: impl AsRef<Path>) -> Self {
+        let root = root.as_ref();
+        let file = PathBuf::from(file.as_ref());
+        let redirects = File::open(&file)
+            .ok()
+            .and_then(|mut fd| serde_json::from_reader(&mut fd).ok())
+            .unwrap_or_default();
+
+        RedirectTable {
+            // Events have canonical paths, so the root should be canonical for comparison.
+            root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            excluded: excluded.iter().map(PathBuf::from).collect(),
+            file,
+            redirects: Arc::new(RwLock::new(redirects)),
+        }
+    }
+
+    /// Get the location for the (decoded) path, if it has been moved.
+    fn get(&self, path: &str) -> Option<String> {
+        let redirects = self.redirects.read().expect("redirects lock poisoned");
+        redirects
+            .get(path)
+            .or_else(|| redirects.get(path.trim_end_matches('/')))
+            .cloned()
+    }
+
+    /// Write the table to disk.
+    fn save(&self, redirects: &BTreeMap<String, String>) {
+        let tmp_path = self.file.with_extension("tmp");
+        let result = File::create(&tmp_path)
+            .and_then(|mut fd| serde_json::to_writer_pretty(&mut fd, redirects).map_err(Into::into))
+            .and_then(|_| fs::rename(&tmp_path, &self.file));
+        if let Err(e) = result {
+            error!("Cannot save redirects to {}: {}", self.file.display(), e);
+        }
+    }
+
+    /// Path relative to root (if it's not excluded).
+    fn rel_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        let rel_path = path.strip_prefix(&self.root).ok()?;
+        if self.excluded.iter().any(|p| rel_path.starts_with(p)) || rel_path.iter().count() == 0 {
+            return None;
+        }
+
+        Some(rel_path)
+    }
+
+    /// Handle a file (or directory) being created at the given path.
+    fn created(&self, path: &Path) {
+        let rel_path = match self.rel_path(path) {
+            Some(p) => p,
+            None => return,
+        };
+
+        // Anything that exists again shouldn't be redirected anymore.
+        let key = format!("/{}", rel_path.display());
+        let mut redirects = self.redirects.write().expect("redirects lock poisoned");
+        let len = redirects.len();
+        redirects.retain(|old, _| old != &key && !old.starts_with(&(key.clone() + "/")));
+        if redirects.len() != len {
+            self.save(&redirects);
+        }
+    }
+
+    /// Handle a file (or directory) being renamed.
+    fn renamed(&self, old_path: &Path, new_path: &Path) {
+        let (old_rel, new_rel) = match (self.rel_path(old_path), self.rel_path(new_path)) {
+            // Editors often save files by renaming temporary (hidden) files.
+            (Some(o), Some(_)) if o.iter().any(|c| watcher::is_hidden(&c.to_string_lossy())) => {
+                return self.created(new_path)
+            }
+            (Some(o), Some(n)) => (o.to_path_buf(), n.to_path_buf()),
+            // Moving things out of (or into) the excluded paths is like removing (or creating) them.
+            (None, Some(_)) => return self.created(new_path),
+            _ => return,
+        };
+
+        self.created(new_path);
+        let mut moved = vec![(old_rel.clone(), new_rel.clone(), new_path.is_dir())];
+        if new_path.is_dir() {
+            // All the files inside the directory have moved as well.
+            let mut dirs = vec![new_path.to_path_buf()];
+            while let Some(dir) = dirs.pop() {
+                let entries = match fs::read_dir(&dir) {
+                    Ok(e) => e,
+                    Err(_) => continue,
+                };
+
+                for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                    let rel = path.strip_prefix(new_path).expect("walking inside dir");
+                    moved.push((old_rel.join(rel), new_rel.join(rel), path.is_dir()));
+                    if path.is_dir() {
+                        dirs.push(path);
+                    }
+                }
+            }
+        }
+
+        let mut redirects = self.redirects.write().expect("redirects lock poisoned");
+        for (old, new, is_dir) in moved {
+            let key = format!("/{}", old.display());
+            let mut location = String::from("/") + &util::encode_path(&new);
+            if is_dir {
+                location.push('/');
+            }
+
+            // Older paths which pointed here should point to the new location.
+            let old_location = String::from("/") + &util::encode_path(&old);
+            for value in redirects.values_mut() {
+                if value.trim_end_matches('/') == old_location {
+                    *value = location.clone();
+                }
+            }
+
+            info!("Redirecting {} to {}", key, location);
+            redirects.insert(key, location);
+        }
+
+        self.save(&redirects);
+    }
+
+    /// Keep the table updated with the renames in the root.
+    pub fn watch(&self, bus: &ChangeBus) {
+        let table = self.clone();
+        bus.listen("redirects", move |change| match change {
+            Change::Created(path) => table.created(&table.root.join(path)),
+            Change::Renamed(old, new) => {
+                table.renamed(&table.root.join(old), &table.root.join(new))
+            }
+            _ => (),
+        });
+    }
+}
+
+/// Middleware which redirects the renamed paths to their new locations.
+pub struct Redirects {
+    table: RedirectTable,
+}
+
+impl Redirects {
+    pub fn new(table: &RedirectTable) -> Self {
+        Redirects {
+            table: table.clone(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<StaticFile> for Redirects {
+    async fn handle(&self, req: Request<StaticFile>, next: Next<'_, StaticFile>) -> tide::Result {
+        if req.method() != Method::Get && req.method() != Method::Head {
+            return Ok(next.run(req).await);
+        }
+
+        let path = percent_encoding::percent_decode_str(req.url().path()).decode_utf8_lossy();
+        let mut location = match self.table.get(&path) {
+            Some(l) => l,
+            None => return Ok(next.run(req).await),
+        };
+
+        // Paths which exist are always served (in case the table is stale).
+        let exists = match req.state().get_path(req.url().path()) {
+            Ok(p) => async_std::fs::metadata(&p).await.is_ok(),
+            Err(_) => false,
+        };
+
+        if exists {
+            return Ok(next.run(req).await);
+        }
+
+        if let Some(query) = req.url().query() {
+            location.push('?');
+            location.push_str(query);
+        }
+
+        Ok(Response::builder(StatusCode::MovedPermanently)
+            .header(LOCATION, location)
+            .build())
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for RedirectTable {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !auth::is_authorized(&req) {
+            return Ok(auth::unauthorized());
+        }
+
+        if req.method() == Method::Delete {
+            // Either a single path (`?path=`) or everything.
+            let path = req
+                .url()
+                .query_pairs()
+                .find(|(k, _)| k == "path")
+                .map(|(_, v)| v.into_owned());
+            let mut redirects = self.redirects.write().expect("redirects lock poisoned");
+            match path {
+                Some(p) if redirects.remove(&p).is_none() => {
+                    return Ok(Response::new(StatusCode::NotFound))
+                }
+                Some(_) => (),
+                None => redirects.clear(),
+            }
+
+            self.save(&redirects);
+            return Ok(Response::new(StatusCode::NoContent));
+        }
+
+        let mut resp = Response::new(StatusCode::Ok);
+        let redirects = self.redirects.read().expect("redirects lock poisoned");
+        resp.set_body(Body::from_json(&*redirects)?);
+        Ok(resp)
+    }
+}
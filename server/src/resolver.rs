@@ -0,0 +1,200 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Reasons for rejecting a request path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResolveError {
+    /// Path contains a NUL byte (raw or encoded).
+    NulByte,
+    /// Path contains an encoded separator (`%2F`, `%5C`) or a backslash.
+    Separator,
+    /// Decoded path is not valid UTF-8 (includes overlong sequences).
+    InvalidUtf8,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ResolveError::NulByte => "path contains a NUL byte",
+            ResolveError::Separator => "path contains an encoded separator",
+            ResolveError::InvalidUtf8 => "path is not valid UTF-8",
+        })
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<ResolveError> for io::Error {
+    fn from(e: ResolveError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Resolve the (percent-encoded) URL path against the given root.
+///
+/// The path is decoded *before* it's split into components, so that nothing
+/// encoded can sneak into a file name. Parent components are applied lexically
+/// and can never climb above the root.
+pub fn resolve(root: &Path, path: &str) -> Result<PathBuf, ResolveError> {
+    let raw = path.as_bytes();
+    // Encoded separators would otherwise become part of a component after decoding.
+    for window in raw.windows(3) {
+        if window[0] == b'%' && window[1] == b'2' && window[2].eq_ignore_ascii_case(&b'f')
+            || window[0] == b'%' && window[1] == b'5' && window[2].eq_ignore_ascii_case(&b'c')
+        {
+            return Err(ResolveError::Separator);
+        }
+    }
+
+    let decoded: Vec<u8> = percent_encoding::percent_decode(raw).collect();
+    if decoded.contains(&0) {
+        return Err(ResolveError::NulByte);
+    }
+
+    if decoded.contains(&b'\\') {
+        return Err(ResolveError::Separator);
+    }
+
+    let decoded = String::from_utf8(decoded).map_err(|_| ResolveError::InvalidUtf8)?;
+    let mut components = vec![];
+    for part in decoded.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+
+    Ok(components
+        .into_iter()
+        .fold(PathBuf::from(root), |path, c| path.join(c)))
+}
+
+/// Normalize the (percent-encoded) URL path the way it's resolved (decoded, and
+/// without the empty, `.` and `..` components), keeping its trailing slash.
+pub fn normalize(path: &str) -> Result<String, ResolveError> {
+    let resolved = resolve(Path::new("/"), path)?;
+    let mut normalized = resolved
+        .to_str()
+        .expect("resolved path isn't UTF-8")
+        .to_owned();
+    if path.ends_with('/') && normalized != "/" {
+        normalized.push('/');
+    }
+
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, resolve, ResolveError};
+    use std::path::{Path, PathBuf};
+
+    const ROOT: &str = "/srv/root";
+
+    #[test]
+    fn test_resolve() {
+        let cases: &[(&str, Result<&str, ResolveError>)] = &[
+            // regular paths
+            ("/", Ok("")),
+            ("", Ok("")),
+            ("/index.html", Ok("index.html")),
+            ("/a/b.txt", Ok("a/b.txt")),
+            ("//a///b", Ok("a/b")),
+            ("/./a/./b/.", Ok("a/b")),
+            ("/a%20b.txt", Ok("a b.txt")),
+            ("/caf%C3%A9.txt", Ok("café.txt")),
+            ("/café.txt", Ok("café.txt")),
+            ("/a/b/", Ok("a/b")),
+            // traversal
+            ("/..", Ok("")),
+            ("/../../etc/passwd", Ok("etc/passwd")),
+            ("/a/b/../../../../c", Ok("c")),
+            ("/a/../b", Ok("b")),
+            ("/a/%2e%2e/%2e%2e/etc/passwd", Ok("etc/passwd")),
+            ("/%2E%2E/%2E%2E/etc/passwd", Ok("etc/passwd")),
+            ("/.%2e/.%2e/etc", Ok("etc")),
+            ("/...", Ok("...")),
+            // double encoding only decodes once, so it's a literal name
+            ("/%252e%252e/x", Ok("%2e%2e/x")),
+            ("/%252F", Ok("%2F")),
+            // encoded separators
+            (
+                "/%2e%2e%2f%2e%2e%2fetc%2fpasswd",
+                Err(ResolveError::Separator),
+            ),
+            ("/a%2Fb", Err(ResolveError::Separator)),
+            ("/a%5c..%5c..%5cetc", Err(ResolveError::Separator)),
+            ("/a%5Cb", Err(ResolveError::Separator)),
+            ("/..\\..\\etc", Err(ResolveError::Separator)),
+            // NUL bytes
+            ("/a%00.txt", Err(ResolveError::NulByte)),
+            ("/index.html%00.png", Err(ResolveError::NulByte)),
+            ("/a\0b", Err(ResolveError::NulByte)),
+            // invalid and overlong UTF-8
+            ("/%ff", Err(ResolveError::InvalidUtf8)),
+            ("/%c0%ae%c0%ae/etc/passwd", Err(ResolveError::InvalidUtf8)),
+            ("/%c0%af", Err(ResolveError::InvalidUtf8)),
+            ("/%e0%80%ae", Err(ResolveError::InvalidUtf8)),
+            ("/%C3", Err(ResolveError::InvalidUtf8)),
+            // malformed escapes are left as they are
+            ("/%zz", Ok("%zz")),
+            ("/100%", Ok("100%")),
+        ];
+
+        for (input, expected) in cases {
+            let expected = expected.map(|p| Path::new(ROOT).join(p));
+            assert_eq!(
+                resolve(Path::new(ROOT), input),
+                expected,
+                "resolving {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let cases: &[(&str, Result<&str, ResolveError>)] = &[
+            ("/", Ok("/")),
+            ("", Ok("/")),
+            ("/a/b.txt", Ok("/a/b.txt")),
+            ("/a/b/", Ok("/a/b/")),
+            ("//private//x/f", Ok("/private/x/f")),
+            ("/%70rivate/x/f", Ok("/private/x/f")),
+            ("/a/../private/x/", Ok("/private/x/")),
+            ("/./a/.", Ok("/a")),
+            ("/..", Ok("/")),
+            ("/../", Ok("/")),
+            ("/a%20b/", Ok("/a b/")),
+            ("/a%2Fb", Err(ResolveError::Separator)),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                normalize(input),
+                expected.map(String::from),
+                "normalizing {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve_stays_in_root() {
+        let root = PathBuf::from(ROOT);
+        for input in &[
+            "/../",
+            "/../../../../../../",
+            "/a/../../..",
+            "/%2e%2e",
+            "/x/%2E%2e/%2e./..",
+        ] {
+            let path = resolve(&root, input).unwrap();
+            assert!(path.starts_with(&root), "{:?} escaped to {:?}", input, path);
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use crate::acl::AccessControl;
+use crate::bus::ChangeBus;
+use crate::protect::Protection;
+use crate::proxy;
+use crate::resolver;
+use tide::{Body, Endpoint, Request, Response, StatusCode};
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 50;
+/// Number of chars on either side of the match in snippets.
+const SNIPPET_CONTEXT: usize = 80;
+/// Matches in the title are worth more than the ones in the body.
+const TITLE_BOOST: f64 = 3.0;
+
+/// Text content of an indexed page.
+struct Document {
+    title: String,
+    text: String,
+    /// Term frequencies in this document.
+    terms: HashMap<String, u32>,
+}
+
+/// A single search result.
+#[derive(Serialize)]
+struct Hit<'a> {
+    path: &'a str,
+    title: &'a str,
+    snippet: String,
+    #[serde(skip)]
+    score: f64,
+}
+
+/// In-memory full-text index of the HTML and Markdown files in a root.
+pub struct SearchIndex {
+    root: PathBuf,
+    /// Paths (relative to root) to be excluded from the index.
+    excluded: Vec<PathBuf>,
+    /// Documents keyed by their URL path.
+    docs: HashMap<String, Document>,
+    /// Number of documents containing each term.
+    doc_freqs: HashMap<String, usize>,
+}
+
+impl SearchIndex {
+    /// Build the index for all the files in the given root (except the excluded sub-paths).
+    pub fn build(root: impl AsRef<Path>, excluded: &[&str]) -> Self {
+        let root = root.as_ref();
+        let mut index = SearchIndex {
+            // Events have canonical paths, so the root should be canonical for comparison.
+            root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            excluded: excluded.iter().map(PathBuf::from).collect(),
+            docs: HashMap::new(),
+            doc_freqs: HashMap::new(),
+        };
+
+        let root = index.root.clone();
+        index.index_dir(&root);
+        info!(
+            "Indexed {} documents in {}",
+            index.docs.len(),
+            index.root.display()
+        );
+        index
+    }
+
+    /// Keep the index updated with the changes in the root.
+    pub fn watch(index: Arc<RwLock<SearchIndex>>, bus: &ChangeBus) {
+        let root = index.read().expect("index lock poisoned").root.clone();
+        bus.listen("search", move |change| {
+            let mut index = index.write().expect("index lock poisoned");
+            for path in change.paths() {
+                index.update(&root.join(path));
+            }
+        });
+    }
+
+    /// Index all the files in the directory (and its sub-directories).
+    fn index_dir(&mut self, dir: &Path) {
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("Cannot read {} for indexing: {}", dir.display(), e);
+                    continue;
+                }
+            };
+
+            for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if self.is_excluded(&path) {
+                    continue;
+                } else if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    self.update(&path);
+                }
+            }
+        }
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        match path.strip_prefix(&self.root) {
+            Ok(rel_path) => self.excluded.iter().any(|p| rel_path.starts_with(p)),
+            Err(_) => true,
+        }
+    }
+
+    /// URL path for the given file (if it's an indexable document).
+    fn url_path(&self, path: &Path) -> Option<String> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        if !matches!(&*ext, "html" | "htm" | "md" | "markdown") {
+            return None;
+        }
+
+        let rel_path = path.strip_prefix(&self.root).ok()?;
+        let mut url = String::from("/") + &crate::util::encode_path(rel_path);
+        // Directories are served using their `index.html`
+        if path.file_name()? == "index.html" {
+            url.truncate(url.len() - "index.html".len());
+        }
+
+        Some(url)
+    }
+
+    /// (Re)index the file (or directory) at the given path, or remove it if it's gone.
+    pub fn update(&mut self, path: &Path) {
+        if self.is_excluded(path) {
+            return;
+        }
+
+        // Removed directories take all their documents with them.
+        if !path.exists() {
+            if let Ok(rel_path) = path.strip_prefix(&self.root) {
+                let prefix = String::from("/") + &crate::util::encode_path(rel_path);
+                let removed = self
+                    .docs
+                    .keys()
+                    .filter(|p| **p == prefix || p.starts_with(&(prefix.clone() + "/")))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for url in removed {
+                    self.remove(&url);
+                }
+            }
+        }
+
+        // New (or moved) directories bring all their documents with them.
+        if path.is_dir() {
+            return self.index_dir(path);
+        }
+
+        let url = match self.url_path(path) {
+            Some(u) => u,
+            None => return,
+        };
+
+        self.remove(&url);
+        let content = match fs::read(path) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(_) => return,
+        };
+
+        let is_html = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase().starts_with("htm"))
+            .unwrap_or(false);
+        let (title, text) = if is_html {
+            extract_html(&content)
+        } else {
+            extract_markdown(&content)
+        };
+
+        let mut terms = HashMap::new();
+        for term in tokenize(&title).chain(tokenize(&text)) {
+            *terms.entry(term).or_insert(0) += 1;
+        }
+
+        for term in terms.keys() {
+            *self.doc_freqs.entry(term.clone()).or_insert(0) += 1;
+        }
+
+        debug!("Indexed {} ({} terms)", url, terms.len());
+        self.docs.insert(url, Document { title, text, terms });
+    }
+
+    fn remove(&mut self, url: &str) {
+        if let Some(doc) = self.docs.remove(url) {
+            for term in doc.terms.keys() {
+                if let Some(c) = self.doc_freqs.get_mut(term) {
+                    *c -= 1;
+                    if *c == 0 {
+                        self.doc_freqs.remove(term);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find the documents (except the hidden ones) containing all terms in the
+    /// query (sorted by TF-IDF).
+    fn search(&self, query: &str, limit: usize, hidden: impl Fn(&str) -> bool) -> Vec<Hit<'_>> {
+        let terms = tokenize(query).collect::<Vec<_>>();
+        if terms.is_empty() {
+            return vec![];
+        }
+
+        let total = self.docs.len() as f64;
+        let mut hits = self
+            .docs
+            .iter()
+            .filter(|(path, doc)| terms.iter().all(|t| doc.terms.contains_key(t)) && !hidden(path))
+            .map(|(path, doc)| {
+                let title_terms = tokenize(&doc.title).collect::<Vec<_>>();
+                let score = terms
+                    .iter()
+                    .map(|t| {
+                        let idf = (total / *self.doc_freqs.get(t).unwrap_or(&1) as f64).ln() + 1.0;
+                        let boost = if title_terms.contains(t) {
+                            TITLE_BOOST
+                        } else {
+                            1.0
+                        };
+                        doc.terms[t] as f64 * idf * boost
+                    })
+                    .sum();
+
+                Hit {
+                    path,
+                    title: &doc.title,
+                    snippet: snippet(&doc.text, &terms),
+                    score,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.path.cmp(b.path)));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+/// Lowercase alphanumeric words in the given text.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+}
+
+/// Decode the common HTML entities.
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Collapse all whitespace into single spaces.
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Extract the title and the visible text from an HTML document.
+fn extract_html(html: &str) -> (String, String) {
+    // Only ASCII is lowercased, so that the offsets are the same as in the original.
+    let lower = html.to_ascii_lowercase();
+    let title = lower
+        .find("<title")
+        .and_then(|start| Some(start + lower[start..].find('>')? + 1))
+        .and_then(|start| Some((start, start + lower[start..].find("</title")?)))
+        .map(|(start, end)| collapse_whitespace(&decode_entities(&html[start..end])))
+        .unwrap_or_default();
+
+    let body_start = lower.find("<body").unwrap_or(0);
+    let mut text = String::new();
+    let mut rest = &html[body_start..];
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+        rest = &rest[start..];
+        let tag = rest.get(1..).unwrap_or("").to_ascii_lowercase();
+        // Skip the contents of scripts and styles.
+        let closing = ["script", "style", "noscript"]
+            .iter()
+            .find(|t| tag.starts_with(*t))
+            .map(|t| format!("</{}", t));
+        let end = match closing {
+            Some(c) => rest
+                .to_ascii_lowercase()
+                .find(&c)
+                .and_then(|i| Some(i + rest[i..].find('>')? + 1)),
+            None => rest.find('>').map(|i| i + 1),
+        };
+
+        rest = &rest[end.unwrap_or(rest.len())..];
+    }
+
+    text.push_str(rest);
+    (title, collapse_whitespace(&decode_entities(&text)))
+}
+
+/// Extract the title (first heading) and the text from a Markdown document.
+fn extract_markdown(md: &str) -> (String, String) {
+    let title = md
+        .lines()
+        .find_map(|l| l.trim().strip_prefix('#'))
+        .map(|t| t.trim_start_matches('#').trim().to_owned())
+        .unwrap_or_default();
+    let text = md
+        .chars()
+        .map(|c| match c {
+            '#' | '*' | '_' | '`' | '>' | '[' | ']' | '|' => ' ',
+            c => c,
+        })
+        .collect::<String>();
+    (title, collapse_whitespace(&text))
+}
+
+/// Snippet of the text around the first occurrence of any of the terms.
+fn snippet(text: &str, terms: &[String]) -> String {
+    let lower = text.to_lowercase();
+    // Lowercasing could change the byte offsets for some chars, so fall back to the start.
+    let pos = if lower.len() == text.len() {
+        terms
+            .iter()
+            .filter_map(|t| lower.find(&**t))
+            .min()
+            .unwrap_or(0)
+    } else {
+        0
+    };
+
+    let mut start = pos.saturating_sub(SNIPPET_CONTEXT);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+
+    let mut end = (pos + SNIPPET_CONTEXT).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+
+    snippet.push_str(text[start..end].trim());
+    if end < text.len() {
+        snippet.push('…');
+    }
+
+    snippet
+}
+
+/// Endpoint for searching the index (`?q=<query>&limit=<n>`).
+#[derive(Clone)]
+pub struct Search {
+    index: Arc<RwLock<SearchIndex>>,
+    /// Protected pages shouldn't show up (even in snippets) for anyone.
+    protection: Protection,
+    /// Nor should the pages which the client isn't allowed to request.
+    access_control: AccessControl,
+}
+
+impl Search {
+    /// Create an endpoint for the given index.
+    pub fn new(
+        index: Arc<RwLock<SearchIndex>>,
+        protection: Protection,
+        access_control: AccessControl,
+    ) -> Self {
+        Search {
+            index,
+            protection,
+            access_control,
+        }
+    }
+
+    /// Whether the page (with its URL path from the index) should be hidden from the client.
+    fn is_hidden(&self, path: &str, ip: Option<IpAddr>) -> bool {
+        self.protection.is_protected(path)
+            || resolver::normalize(path)
+                .map_or(true, |served| !self.access_control.is_allowed(&served, ip))
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for Search {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let (mut query, mut limit) = (None, DEFAULT_LIMIT);
+        for (key, value) in req.url().query_pairs() {
+            match &*key {
+                "q" => query = Some(value.into_owned()),
+                "limit" => limit = value.parse().unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+                _ => (),
+            }
+        }
+
+        let query = match query.filter(|q| !q.trim().is_empty()) {
+            Some(q) => q,
+            None => {
+                return Ok(Response::builder(StatusCode::BadRequest)
+                    .body("missing query (q)")
+                    .build())
+            }
+        };
+
+        let ip = proxy::client_ip(&req);
+        let index = self.index.read().expect("index lock poisoned");
+        let hits = index.search(&query, limit, |p| self.is_hidden(p, ip));
+        let mut resp = Response::new(StatusCode::Ok);
+        resp.set_body(Body::from_json(&serde_json::json!({
+            "query": query,
+            "results": hits,
+        }))?);
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_html, Search, SearchIndex};
+    use crate::acl::{AccessControl, AccessRule};
+    use crate::protect::Protection;
+    use crate::settings::{AccessControlRule, ProtectRule, ProtectSettings};
+    use std::fs;
+    use std::net::IpAddr;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn test_extract_html() {
+        let cases: &[(&str, &str, &str)] = &[
+            (
+                "<html><head><title>Hello &amp; bye</title></head><body><p>Some <b>text</b></p></body></html>",
+                "Hello & bye",
+                "Some text",
+            ),
+            (
+                "<HTML><TITLE>\n  Upper\n  case </TITLE><BODY>Text<SCRIPT>var x = '<p>';</SCRIPT> more</BODY>",
+                "Upper case",
+                "Text more",
+            ),
+            (
+                "<body>a<style>p { color: red }</style>b<noscript>c</noscript>d</body>",
+                "",
+                "a b d",
+            ),
+            // Lowercasing these (outside ASCII) changes their lengths.
+            (
+                "İİİ <title>Türkçe İçerik</title><body>İstanbul <script>İ</script>ẞ ok</body>",
+                "Türkçe İçerik",
+                "İstanbul ẞ ok",
+            ),
+            (
+                "<title>Ⱥ</title>ȺȺȺȺ<body>Ⱥ<STYLE>Ⱥ</STYLE>Ⱥ</body>",
+                "Ⱥ",
+                "Ⱥ Ⱥ",
+            ),
+        ];
+
+        for (html, title, text) in cases {
+            assert_eq!(
+                extract_html(html),
+                (title.to_string(), text.to_string()),
+                "extracting {:?}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_hidden_results() {
+        let root = std::env::temp_dir().join(format!("search-test-{}", std::process::id()));
+        for path in [
+            "index.html",
+            "administrator.html",
+            "admin/report.html",
+            "admin/été.html",
+            "drafts/a.html",
+        ] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().expect("parent")).expect("test dir");
+            fs::write(&path, "<title>Waffles</title><body>waffles</body>").expect("page");
+        }
+
+        let index = SearchIndex::build(&root, &[]);
+        fs::remove_dir_all(&root).expect("test dir removed");
+
+        let protection = Protection::new(&ProtectSettings {
+            rules: vec![ProtectRule {
+                paths: vec![String::from("/drafts")],
+                credentials: String::from("family"),
+                mode: Default::default(),
+            }],
+            ..Default::default()
+        });
+        let access_control = AccessControl::new(
+            &[AccessControlRule {
+                prefix: String::from("/admin"),
+                rules: vec![
+                    AccessRule::Allow(vec!["10.0.0.0/8".parse().expect("valid range")]),
+                    AccessRule::Deny(vec!["0.0.0.0/0".parse().expect("valid range")]),
+                ],
+            }],
+            Default::default(),
+            403,
+        );
+        let search = Search::new(Arc::new(RwLock::new(index)), protection, access_control);
+
+        let cases: &[(Option<&str>, &[&str])] = &[
+            (
+                Some("10.1.2.3"),
+                &[
+                    "/",
+                    "/admin/%C3%A9t%C3%A9.html",
+                    "/admin/report.html",
+                    "/administrator.html",
+                ],
+            ),
+            (Some("192.0.2.1"), &["/", "/administrator.html"]),
+            (None, &["/", "/administrator.html"]),
+        ];
+
+        let index = search.index.read().expect("index lock");
+        for (ip, expected) in cases {
+            let ip = ip.map(|ip| ip.parse::<IpAddr>().expect("valid IP"));
+            let hits = index.search("waffles", 10, |p| search.is_hidden(p, ip));
+            let mut paths = hits.iter().map(|h| h.path).collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(paths, *expected, "results for {:?}", ip);
+        }
+    }
+}
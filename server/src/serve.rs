@@ -0,0 +1,313 @@
+use crate::metrics;
+use async_std::net::TcpListener;
+use async_std::task;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, Future};
+use futures::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use futures::{StreamExt, TryStreamExt};
+use http::header::HOST;
+use http::Version;
+use http_body_util::BodyStream;
+use http_types::{Method, StatusCode, Url};
+use hyper::body::{Frame, Incoming, SizeHint};
+use hyper::service::service_fn;
+use hyper_util::server::conn::auto;
+use smol_hyper::rt::{FuturesIo, SmolTimer};
+use tide::Server;
+
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Maximum size of a single frame in the response body.
+const FRAME_SIZE: usize = 64 * 1024;
+/// Connection-specific headers, which are not allowed in HTTP/2.
+const HOP_BY_HOP_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Executor for the background tasks of hyper (e.g., HTTP/2 streams).
+#[derive(Clone, Copy)]
+struct Executor;
+
+impl<F> hyper::rt::Executor<F> for Executor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        task::spawn(fut);
+    }
+}
+
+/// Response body which streams the body of a tide response.
+struct ResponseBody {
+    body: http_types::Body,
+    remaining: Option<u64>,
+}
+
+impl hyper::body::Body for ResponseBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = &mut *self;
+        let mut chunk = BytesMut::new();
+        // Gather whatever is available (up to a frame), instead of sending tiny frames.
+        while chunk.len() < FRAME_SIZE {
+            let len = match Pin::new(&mut this.body).poll_fill_buf(cx) {
+                Poll::Ready(Ok([])) => break,
+                Poll::Ready(Ok(buf)) => {
+                    chunk.extend_from_slice(buf);
+                    buf.len()
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending if chunk.is_empty() => return Poll::Pending,
+                Poll::Pending => break,
+            };
+
+            Pin::new(&mut this.body).consume(len);
+        }
+
+        if chunk.is_empty() {
+            return Poll::Ready(None);
+        }
+
+        if let Some(r) = this.remaining.as_mut() {
+            *r = r.saturating_sub(chunk.len() as u64);
+        }
+
+        Poll::Ready(Some(Ok(Frame::data(chunk.freeze()))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.remaining {
+            Some(len) => SizeHint::with_exact(len),
+            None => SizeHint::default(),
+        }
+    }
+}
+
+// The app (its endpoints and middlewares) is still written against tide, so hyper
+// only handles the connections for now, and the requests and responses are converted
+// here. This is an interim step: trailers need the app itself to be ported to the types
+// of hyper (which would also make these conversions go away).
+
+/// Convert the request from hyper into one for tide (`None` if it's not supported).
+fn to_request<B>(
+    req: hyper::Request<B>,
+    scheme: &str,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+) -> Option<http_types::Request>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + Sync + Unpin + 'static,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    let (parts, body) = req.into_parts();
+    // HTTP/2 has the host in the URI, while HTTP/1.1 has it in the header.
+    let host = match parts.uri.authority() {
+        Some(a) => a.to_string(),
+        None => match parts.headers.get(HOST) {
+            Some(h) => h.to_str().ok()?.to_owned(),
+            None => local_addr.to_string(),
+        },
+    };
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let url = Url::parse(&format!("{}://{}{}", scheme, host, path)).ok()?;
+    let method = parts.method.as_str().parse::<Method>().ok()?;
+    let mut req = http_types::Request::new(method, url);
+    req.set_version(Some(match parts.version {
+        Version::HTTP_10 => http_types::Version::Http1_0,
+        Version::HTTP_2 => http_types::Version::Http2_0,
+        _ => http_types::Version::Http1_1,
+    }));
+
+    for (name, value) in &parts.headers {
+        // Only the visible ASCII values can be represented in tide, and the request
+        // shouldn't be handled as if the other ones weren't there.
+        match value.to_str() {
+            Ok(v) => req.append_header(name.as_str(), v),
+            Err(_) => {
+                debug!("Invalid value of {} from {}", name, peer_addr);
+                return None;
+            }
+        }
+    }
+
+    let len = req
+        .header(http_types::headers::CONTENT_LENGTH)
+        .and_then(|v| v.as_str().parse().ok());
+    let reader = BodyStream::new(body)
+        .try_filter_map(|frame| future::ready(Ok(frame.into_data().ok())))
+        .map_err(io::Error::other)
+        .into_async_read();
+    req.set_body(http_types::Body::from_reader(reader, len));
+    req.set_local_addr(Some(local_addr));
+    req.set_peer_addr(Some(peer_addr));
+    Some(req)
+}
+
+/// Convert the response from tide into one for hyper.
+fn to_response(mut res: http_types::Response, version: Version) -> hyper::Response<ResponseBody> {
+    let mut builder = hyper::Response::builder().status(res.status() as u16);
+    for (name, values) in res.iter() {
+        if version == Version::HTTP_2 && HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+
+        for value in values {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+    }
+
+    let body = res.take_body();
+    let body = ResponseBody {
+        remaining: body.len().map(|l| l as u64),
+        body,
+    };
+
+    builder.body(body).unwrap_or_else(|e| {
+        error!("Cannot convert response: {}", e);
+        let mut res = hyper::Response::new(ResponseBody {
+            body: http_types::Body::empty(),
+            remaining: Some(0),
+        });
+        *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+        res
+    })
+}
+
+/// Serve the app over the connection (HTTP/1.1, or HTTP/2 if the client asks for it).
+pub async fn serve_connection<State, IO>(
+    app: Server<State>,
+    io: IO,
+    scheme: &'static str,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+) where
+    State: Clone + Send + Sync + 'static,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let _connection = metrics::Connection::open();
+    let service = service_fn(move |req: hyper::Request<Incoming>| {
+        let app = app.clone();
+        async move {
+            let version = req.version();
+            let res = match to_request(req, scheme, local_addr, peer_addr) {
+                Some(req) => app
+                    .respond(req)
+                    .await
+                    .unwrap_or_else(|e| http_types::Response::new(e.status())),
+                None => http_types::Response::new(StatusCode::BadRequest),
+            };
+
+            Ok::<_, Infallible>(to_response(res, version))
+        }
+    });
+
+    let mut builder = auto::Builder::new(Executor);
+    builder.http1().timer(SmolTimer::new());
+    builder.http2().timer(SmolTimer::new());
+    if let Err(e) = builder.serve_connection(FuturesIo::new(io), service).await {
+        debug!("Error in connection from {}: {}", peer_addr, e);
+    }
+}
+
+/// Serve the app over plain HTTP on the given address (including HTTP/2 with
+/// prior knowledge).
+pub async fn listen<State: Clone + Send + Sync + 'static>(
+    app: Server<State>,
+    address: &str,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Server listening on http://{}", listener.local_addr()?);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Cannot accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let (local_addr, peer_addr) = match (stream.local_addr(), stream.peer_addr()) {
+            (Ok(l), Ok(p)) => (l, p),
+            _ => continue,
+        };
+
+        // Responses are written in a few pieces, which shouldn't wait for each other.
+        if let Err(e) = stream.set_nodelay(true) {
+            debug!("Cannot disable Nagle's algorithm for {}: {}", peer_addr, e);
+        }
+
+        task::spawn(serve_connection(
+            app.clone(),
+            stream,
+            "http",
+            local_addr,
+            peer_addr,
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_request;
+    use bytes::Bytes;
+    use http::HeaderValue;
+    use http_body_util::Empty;
+
+    #[test]
+    fn test_header_values() {
+        let cases: &[(&[u8], Option<&str>)] = &[
+            (b"plain", Some("plain")),
+            (b"a\tb c", Some("a\tb c")),
+            (b"", Some("")),
+            // UTF-8 and other bytes outside visible ASCII can't be carried over.
+            ("café".as_bytes(), None),
+            (b"obs\xfftext", None),
+        ];
+
+        let addr = "127.0.0.1:8000".parse().expect("valid address");
+        for (value, expected) in cases {
+            let req = hyper::Request::get("/a.html")
+                .header(
+                    "X-Test",
+                    HeaderValue::from_bytes(value).expect("valid value"),
+                )
+                .body(Empty::<Bytes>::new())
+                .expect("valid request");
+            let converted = to_request(req, "http", addr, addr);
+            assert_eq!(
+                converted
+                    .as_ref()
+                    .map(|r| r.header("X-Test").map_or("", |v| v.as_str())),
+                *expected,
+                "converting {:?}",
+                String::from_utf8_lossy(value)
+            );
+        }
+    }
+}
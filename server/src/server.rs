@@ -1,49 +1,114 @@
+use crate::access::AccessLog;
+use crate::acl::AccessControl;
+use crate::acme::{self, Challenges, CHALLENGE_PATH_PREFIX};
+use crate::bus::{Change, ChangeBus};
+use crate::health::{Probes, Readiness};
+use crate::images::ImageResizer;
+use crate::links::LinkChecker;
+use crate::metrics::{self, Metrics};
+use crate::minify::Minifier;
+use crate::missing::MissingPaths;
+use crate::preload::Preloader;
+use crate::protect::{self, Login, Protection, LOGIN_PATH, LOGOUT_PATH};
+use crate::proxy::{self, TrustedProxies};
+use crate::ratelimit::RateLimiter;
+use crate::redirects::{RedirectTable, Redirects};
+use crate::resolver;
+use crate::search::{Search, SearchIndex};
+use crate::serve;
+use crate::settings;
+use crate::shutdown::{self, Shutdown};
 use crate::staticfile::{Responder, StaticFile};
+use crate::tls::{self, CertResolver};
+use crate::upload::Upload;
 use crate::util;
-use crate::watcher::PrivateWatcher;
+use crate::watcher::{PrivateHit, PrivateWatcher};
+use crate::webdav::{WebDav, SHARE_MOUNT};
+use futures::future::{self, Either, Future};
+use http_types::headers::CACHE_CONTROL;
 use tide::{Middleware, Next, Request, Response, Server};
 use uuid::Uuid;
 
 use crossbeam_channel::Sender;
-use std::path::Path;
-use std::{env, thread};
-
-const PRIVATE_PATH_PREFIX: &str = "/private";
-
-lazy_static! {
-    static ref DEFAULT_ADDRESS: String =
-        env::var("ADDRESS").unwrap_or(String::from("localhost:8000"));
-    pub static ref SERVE_PATH_ROOT: String = env::var("SOURCE").unwrap_or(String::from("./source"));
-    pub static ref PRIVATE_SERVE_PATH: String = SERVE_PATH_ROOT.clone() + PRIVATE_PATH_PREFIX;
-    pub static ref PRIVATE_PATH_ROOT: String =
-        env::var("PRIVATE_SOURCE").unwrap_or(String::from("./private"));
-    pub static ref CONFIG_FILE: String = env::var("CONFIG").unwrap_or(String::from("config.json"));
+use std::borrow::Cow;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+pub const PRIVATE_PATH_PREFIX: &str = "/private";
+pub const UPLOAD_PATH_PREFIX: &str = "/_upload";
+pub const DAV_PATH_PREFIX: &str = "/_dav";
+pub const SEARCH_PATH: &str = "/search";
+pub const ADMIN_PATH_PREFIX: &str = "/_admin";
+
+/// Path of the request as it's served (see `resolver::normalize`), so that the
+/// checks on it can't be bypassed by encoding the path (or padding it with slashes).
+pub fn served_path<State>(req: &Request<State>) -> Cow<'_, str> {
+    match resolver::normalize(req.url().path()) {
+        Ok(p) => Cow::Owned(p),
+        // Such paths are rejected by everything which serves them.
+        Err(_) => Cow::Borrowed(req.url().path()),
+    }
+}
+
+/// Whether the (served) path is the prefix or inside it, so that `/admin` covers
+/// `/admin/x` but not `/administrator` (and a trailing slash in the prefix doesn't matter).
+pub fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Token of the private entry (which might not be valid) in the served path,
+/// for the private links and the WebDAV shares.
+pub fn private_token(path: &str) -> Option<&str> {
+    path.strip_prefix(PRIVATE_PATH_PREFIX)
+        .or_else(|| {
+            path.strip_prefix(DAV_PATH_PREFIX)
+                .and_then(|p| p.strip_prefix('/'))
+                .and_then(|p| p.strip_prefix(SHARE_MOUNT))
+        })
+        .and_then(|p| p.strip_prefix('/'))
+        .map(|p| p.split('/').next().unwrap_or(""))
 }
 
 struct PrivateMiddleware {
-    sender: Sender<(Uuid, String)>,
+    sender: Sender<PrivateHit>,
 }
 
 #[async_trait::async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for PrivateMiddleware {
     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
-        let path = req.url().path();
-        if !path.starts_with(PRIVATE_PATH_PREFIX) {
+        // Paths with tokens include the WebDAV shares (which send their own accesses).
+        let path = served_path(&req).into_owned();
+        if private_token(&path).is_none() {
             return Ok(next.run(req).await);
         }
 
-        let mut path_iter = path.split("/");
-        let _root = path_iter.next();
-        let _prefix = path_iter.next();
-
-        if let (Some(uuid), Some(sub_path)) = (
-            path_iter.next().and_then(|v| v.parse::<Uuid>().ok()),
-            path_iter.next(),
-        ) {
-            let _ = self.sender.send((uuid, sub_path.into()));
+        if let Some(rest) = path.strip_prefix(PRIVATE_PATH_PREFIX) {
+            let mut path_iter = rest.split('/').skip(1);
+            if let (Some(uuid), Some(sub_path)) = (
+                path_iter.next().and_then(|v| v.parse::<Uuid>().ok()),
+                path_iter.next(),
+            ) {
+                let _ = self
+                    .sender
+                    .send((uuid, sub_path.into(), proxy::client_ip(&req)));
+            }
         }
 
-        Ok(next.run(req).await)
+        // Private responses (including errors for expired tokens) shouldn't outlive
+        // the token in caches, or leak it to other sites.
+        let mut resp = next.run(req).await;
+        resp.insert_header(CACHE_CONTROL, "private, no-store");
+        resp.insert_header("Referrer-Policy", "no-referrer");
+        resp.insert_header("X-Robots-Tag", "noindex, nofollow");
+        Ok(resp)
     }
 }
 
@@ -53,49 +118,286 @@ async fn fetch_file(req: Request<StaticFile>) -> Result<Response, tide::Error> {
 }
 
 pub async fn start() {
-    util::prepare_logger();
-    util::create_dir_if_not_exists(&*PRIVATE_PATH_ROOT);
-    util::create_dir_if_not_exists(&*SERVE_PATH_ROOT);
+    let settings = settings::get();
+    let private_serve_path = settings.private_serve_path();
+    util::prepare_logger(&settings.log_level);
+    util::create_dir_if_not_exists(&settings.private_source);
+    util::create_dir_if_not_exists(&settings.source);
+    util::create_dir_if_not_exists(&settings.image_cache);
+    if settings.acme.is_enabled() {
+        util::create_dir_if_not_exists(&settings.acme.dir);
+    }
 
     info!(
         "Initializing watcher (private source: {}, private serve: {}, config: {}).",
-        &*PRIVATE_PATH_ROOT, &*PRIVATE_SERVE_PATH, &*CONFIG_FILE
+        settings.private_source.display(),
+        private_serve_path.display(),
+        settings.config.display()
+    );
+    let mut watcher = PrivateWatcher::new(
+        &settings.config,
+        &settings.private_source,
+        &private_serve_path,
+        &settings.watcher,
     );
-    let mut watcher = PrivateWatcher::new(&*CONFIG_FILE, &*PRIVATE_PATH_ROOT, &*PRIVATE_SERVE_PATH);
     let sender = watcher.initialize();
+    let links = watcher.links();
+    let access = watcher.access();
+    let stop_watcher = watcher.stop_handle();
+    let heartbeat = watcher.heartbeat();
 
-    let _ = thread::spawn(move || {
+    let watcher_thread = thread::spawn(move || {
         watcher.start_watching();
     });
 
     info!(
         "Initializing staticfile handler to point to {}",
-        &*SERVE_PATH_ROOT
+        settings.source.display()
     );
 
-    let mut static_file = StaticFile::new(&*SERVE_PATH_ROOT);
-    let (path_4xx, path_5xx) = (
-        Path::new(&*SERVE_PATH_ROOT).join("4xx.html"),
-        Path::new(&*SERVE_PATH_ROOT).join("5xx.html"),
-    );
+    let mut static_file = StaticFile::new(&settings.source);
+    static_file.load_error_pages();
+
+    // Changes in the public root are published for everything that depends on the files.
+    let bus = ChangeBus::default();
+    bus.watch(&settings.source);
+    let pages = static_file.clone();
+    bus.listen("error-pages", move |change| {
+        if change.paths().into_iter().any(StaticFile::is_error_page) {
+            pages.load_error_pages();
+        }
+    });
+
+    if settings.minify {
+        info!("Minifying HTML, CSS and JS files");
+        let minifier = Minifier::default();
+        static_file.set_minifier(Some(minifier.clone()));
+        let root = settings.source.clone();
+        bus.listen("minifier", move |change| {
+            change
+                .paths()
+                .into_iter()
+                .for_each(|p| minifier.invalidate(&root.join(p)))
+        });
+    }
 
-    if path_4xx.exists() {
-        info!("Using custom 4xx.html");
-        if let Ok(bytes) = util::read_file(&path_4xx) {
-            static_file.body_4xx = bytes;
+    if settings.preload_manifest.is_some() || settings.preload_discover {
+        match Preloader::new(
+            settings.preload_manifest.as_deref(),
+            settings.preload_discover,
+        ) {
+            Ok(preloader) => {
+                static_file.set_preloader(Some(preloader.clone()));
+                let root = settings.source.clone();
+                bus.listen("preloader", move |change| {
+                    change
+                        .paths()
+                        .into_iter()
+                        .for_each(|p| preloader.invalidate(&root.join(p)))
+                });
+            }
+            Err(e) => error!("Cannot load preload manifest: {}", e),
         }
     }
 
-    if path_5xx.exists() {
-        info!("Using custom 5xx.html");
-        if let Ok(bytes) = util::read_file(&path_5xx) {
-            static_file.body_5xx = bytes;
+    let resizer = ImageResizer::new(&settings.image_cache);
+    let (root, variants) = (settings.source.clone(), resizer.clone());
+    bus.listen("images", move |change| match change {
+        Change::Modified(p) | Change::Removed(p) | Change::Renamed(p, _) => {
+            variants.invalidate(&root.join(p))
         }
+        Change::Created(_) => (),
+    });
+
+    let missing = MissingPaths::default();
+    static_file.set_missing_paths(Some(missing.clone()));
+    if let Some(minutes) = settings.missing_report_interval {
+        missing.notify_every(Duration::from_secs(minutes * 60));
     }
 
+    let redirects = RedirectTable::load(
+        &settings.source,
+        &[PRIVATE_PATH_PREFIX.trim_start_matches('/')],
+        &settings.redirects,
+    );
+    redirects.watch(&bus);
+
+    info!("Building search index for {}", settings.source.display());
+    let index = Arc::new(RwLock::new(SearchIndex::build(
+        &settings.source,
+        &[PRIVATE_PATH_PREFIX.trim_start_matches('/')],
+    )));
+    SearchIndex::watch(index.clone(), &bus);
+
+    let dav = WebDav::new(
+        &static_file,
+        &settings.private_source,
+        &private_serve_path,
+        links.clone(),
+        sender.clone(),
+    );
+
+    let links_checker =
+        LinkChecker::new(&static_file, &[PRIVATE_PATH_PREFIX.trim_start_matches('/')]);
+
+    let shutdown = Shutdown::default();
     let mut app = Server::with_state(static_file);
+    app.with(shutdown.clone());
+    app.with(TrustedProxies::new(&settings.trusted_proxies));
+    let access_log = AccessLog::new(&settings.access_log).expect("cannot open access log");
+    if let Some(access_log) = access_log {
+        app.with(access_log);
+    }
+
+    app.with(Metrics);
+    app.with(Probes(Readiness::new(
+        &settings.source,
+        &settings.config,
+        heartbeat,
+        settings.watcher.sleep_duration(),
+        shutdown.clone(),
+    )));
+    let access_control = AccessControl::new(
+        &settings.access_control,
+        access,
+        settings.access_denied_status,
+    );
+    app.with(access_control.clone());
+    app.with(RateLimiter::new(&settings.rate_limit, links.clone()));
+    let protection = Protection::new(&settings.protect);
+    if protection.has_form() {
+        app.with(Protection::sessions(settings.protect.session_ttl_hours));
+    }
+
+    app.with(protection.clone());
     app.with(PrivateMiddleware { sender });
+    app.with(Redirects::new(&redirects));
+    app.with(resizer);
+    let upload = || {
+        Upload::new(
+            &settings.private_source,
+            &private_serve_path,
+            links.clone(),
+            settings.upload_size_limit,
+        )
+    };
+    app.at(UPLOAD_PATH_PREFIX).post(upload());
+    app.at(&format!("{}/*", UPLOAD_PATH_PREFIX))
+        .put(upload())
+        .post(upload());
+    // GET has to be registered explicitly, or it'll be routed to the static files.
+    app.at(DAV_PATH_PREFIX).get(dav.clone()).all(dav.clone());
+    app.at(&format!("{}/*", DAV_PATH_PREFIX))
+        .get(dav.clone())
+        .all(dav);
+    if protection.has_form() {
+        app.at(LOGIN_PATH)
+            .get(Login(protection.clone()))
+            .post(Login(protection.clone()));
+        app.at(LOGOUT_PATH)
+            .get(protect::logout)
+            .post(protect::logout);
+    }
+
+    app.at(SEARCH_PATH)
+        .get(Search::new(index, protection, access_control));
+    app.at(&format!("{}/missing", ADMIN_PATH_PREFIX))
+        .get(missing.clone())
+        .delete(missing);
+    app.at(&format!("{}/redirects", ADMIN_PATH_PREFIX))
+        .get(redirects.clone())
+        .delete(redirects);
+    app.at(&format!("{}/links", ADMIN_PATH_PREFIX))
+        .get(links_checker);
+    let challenges = Challenges::default();
+    if settings.acme.is_enabled() {
+        app.at(&format!("{}/*", CHALLENGE_PATH_PREFIX))
+            .get(challenges.clone());
+    }
+
     app.at("/").get(fetch_file);
     app.at("/*").get(fetch_file);
-    app.listen(&*DEFAULT_ADDRESS).await.expect("serving");
+
+    // Stop accepting connections on signal (by dropping the listener), drain the
+    // in-flight responses, and then let the watcher finish its current iteration.
+    let signal = shutdown::signal();
+    let mut listeners: Vec<Pin<Box<dyn Future<Output = io::Result<()>>>>> =
+        vec![Box::pin(serve::listen(app.clone(), &settings.address))];
+    if let Some(address) = &settings.tls.address {
+        let mut certificates = settings.tls.certificates.clone();
+        if settings.acme.is_enabled() {
+            // Managed certificate is the default one.
+            certificates.insert(0, acme::certificate(&settings.acme));
+        }
+
+        let resolver = CertResolver::new(&certificates);
+        resolver.watch();
+        if settings.acme.is_enabled() {
+            acme::start(&settings.acme, challenges, resolver.clone());
+        }
+
+        listeners.push(Box::pin(tls::listen(app.clone(), address, resolver)));
+    }
+
+    // Metrics are served separately, so that they're not exposed through the proxy.
+    if let Some(address) = &settings.metrics_address {
+        let mut admin = tide::new();
+        admin.at("/metrics").get(metrics::export);
+        listeners.push(Box::pin(serve::listen(admin, address)));
+    }
+
+    match future::select(future::try_join_all(listeners), signal).await {
+        Either::Left((result, _)) => {
+            result.expect("serving");
+        }
+        Either::Right(_) => (),
+    }
+
+    let timeout = Duration::from_secs(settings.shutdown_timeout_secs);
+    if shutdown.drain(timeout).await {
+        info!("Drained all in-flight requests.");
+    }
+
+    stop_watcher.store(true, Ordering::SeqCst);
+    let _ = watcher_thread.join();
+    info!("Shutdown complete.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::has_path_prefix;
+
+    #[test]
+    fn test_has_path_prefix() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("/admin", "/admin", true),
+            ("/admin/", "/admin", true),
+            ("/admin/users", "/admin", true),
+            ("/administrator", "/admin", false),
+            ("/admin.html", "/admin", false),
+            ("/adm", "/admin", false),
+            ("/", "/admin", false),
+            // trailing slashes in the prefix
+            ("/admin", "/admin/", true),
+            ("/admin/users", "/admin/", true),
+            ("/administrator", "/admin/", false),
+            // root covers everything
+            ("/", "/", true),
+            ("/a/b", "/", true),
+            ("/a/b", "", true),
+            // nested prefixes
+            ("/a/b/c", "/a/b", true),
+            ("/a/bc", "/a/b", false),
+        ];
+
+        for (path, prefix, expected) in cases {
+            assert_eq!(
+                has_path_prefix(path, prefix),
+                *expected,
+                "{:?} in {:?}",
+                path,
+                prefix
+            );
+        }
+    }
 }
@@ -0,0 +1,1281 @@
+use crate::acl::AccessRule;
+use crate::cidr::Cidr;
+use argon2::PasswordHash;
+use clap::builder::BoolishValueParser;
+use clap::{Parser, Subcommand, ValueEnum};
+use globset::Glob;
+use log::LevelFilter;
+use rusoto_core::Region;
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Settings file which is loaded (if it exists) when it's not specified.
+const DEFAULT_SETTINGS_FILE: &str = "server.toml";
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Settings for the watcher of the private root.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherSettings {
+    /// Interval for checking the events and the config.
+    pub sleep_duration_ms: u64,
+    /// Interval for sending the accesses of private paths.
+    pub sms_duration_secs: u64,
+}
+
+impl Default for WatcherSettings {
+    fn default() -> Self {
+        WatcherSettings {
+            sleep_duration_ms: 1000,
+            sms_duration_secs: 5 * 60,
+        }
+    }
+}
+
+impl WatcherSettings {
+    pub fn sleep_duration(&self) -> Duration {
+        Duration::from_millis(self.sleep_duration_ms)
+    }
+
+    pub fn sms_duration(&self) -> Duration {
+        Duration::from_secs(self.sms_duration_secs)
+    }
+}
+
+/// Settings for sending messages (through AWS SNS).
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmsSettings {
+    /// Receiver number (E.164 format).
+    pub receiver: Option<String>,
+    /// AWS region for SNS (credentials are taken from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`).
+    pub aws_region: Option<String>,
+    /// Maximum length of a single message.
+    pub limit: usize,
+}
+
+impl Default for SmsSettings {
+    fn default() -> Self {
+        SmsSettings {
+            receiver: None,
+            aws_region: None,
+            limit: 140, // 160 GSM chars or 140 ASCII chars
+        }
+    }
+}
+
+/// Certificate (and its key) for some hosts.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateSettings {
+    /// Hosts (SNI names, which can be wildcards) for this certificate. The first
+    /// certificate is also used for any other host.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// PEM file with the certificate chain.
+    pub cert: PathBuf,
+    /// PEM file with the private key.
+    pub key: PathBuf,
+}
+
+/// Settings for the HTTPS listener.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// Address to listen on for HTTPS (disabled if unset).
+    pub address: Option<String>,
+    pub certificates: Vec<CertificateSettings>,
+}
+
+/// Settings for obtaining (and renewing) a certificate through ACME.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeSettings {
+    /// Domains for the certificate (disabled if empty).
+    pub domains: Vec<String>,
+    /// Directory URL of the ACME server.
+    pub directory: String,
+    /// Contact for the account (e.g. `mailto:admin@example.com`).
+    pub contact: Option<String>,
+    /// Directory for storing the account key and the certificate.
+    pub dir: PathBuf,
+    /// Additional CA certificate (PEM) for trusting the ACME server (e.g. Pebble).
+    pub ca_cert: Option<PathBuf>,
+    /// Days before expiry when the certificate should be renewed.
+    pub renew_before_days: u64,
+}
+
+impl Default for AcmeSettings {
+    fn default() -> Self {
+        AcmeSettings {
+            domains: vec![],
+            directory: String::from("https://acme-v02.api.letsencrypt.org/directory"),
+            contact: None,
+            dir: PathBuf::from("./acme"),
+            ca_cert: None,
+            renew_before_days: 30,
+        }
+    }
+}
+
+impl AcmeSettings {
+    pub fn is_enabled(&self) -> bool {
+        !self.domains.is_empty()
+    }
+}
+
+/// Format of the access log.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Off,
+    /// Common Log Format
+    Common,
+    /// Combined Log Format (with the referrer and user agent)
+    Combined,
+    /// JSON lines
+    Json,
+}
+
+/// Settings for logging the requests.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogSettings {
+    pub format: AccessLogFormat,
+    /// File for the access log (stdout if unset).
+    pub file: Option<PathBuf>,
+    /// Size (in MiB) at which the file is rotated.
+    pub max_size_mb: u64,
+    /// Number of rotated files to keep.
+    pub keep: usize,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        AccessLogSettings {
+            format: AccessLogFormat::Off,
+            file: None,
+            max_size_mb: 100,
+            keep: 5,
+        }
+    }
+}
+
+/// Rules for the client IPs requesting the paths with the prefix.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessControlRule {
+    pub prefix: String,
+    /// Ordered rules (the first one with a matching range applies).
+    pub rules: Vec<AccessRule>,
+}
+
+/// Budget of the requests from each client IP for the paths with the prefix.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    pub prefix: String,
+    /// Requests per minute (the rate at which the budget refills).
+    pub per_minute: f64,
+    /// Requests which can be made at once.
+    pub burst: u32,
+}
+
+/// Settings for limiting the rate of the requests.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    /// Rules for the path prefixes (the longest matching prefix applies).
+    pub rules: Vec<RateLimitRule>,
+    /// Requests per minute with invalid or expired private tokens.
+    pub invalid_token_per_minute: f64,
+    pub invalid_token_burst: u32,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            rules: vec![],
+            invalid_token_per_minute: 10.0,
+            invalid_token_burst: 10,
+        }
+    }
+}
+
+/// How the clients log in to the protected paths.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtectMode {
+    /// HTTP Basic authentication
+    #[default]
+    Basic,
+    /// Login page, which sets a session cookie
+    Form,
+}
+
+/// Paths protected by a set of credentials.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtectRule {
+    /// Globs for the paths (where everything under a matching directory is also protected).
+    pub paths: Vec<String>,
+    /// Name of the credential set (which is also the realm).
+    pub credentials: String,
+    #[serde(default)]
+    pub mode: ProtectMode,
+}
+
+/// Settings for protecting the public paths with passwords.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtectSettings {
+    /// Credential sets, with the users mapped to their argon2 hashes (in PHC format).
+    pub credentials: HashMap<String, HashMap<String, String>>,
+    /// Rules for the paths (the first one with a matching glob applies).
+    pub rules: Vec<ProtectRule>,
+    /// Failed attempts per minute from each client IP.
+    pub failed_per_minute: f64,
+    pub failed_burst: u32,
+    /// Hours for which the logins through the login page last.
+    pub session_ttl_hours: u64,
+}
+
+impl Default for ProtectSettings {
+    fn default() -> Self {
+        ProtectSettings {
+            credentials: HashMap::new(),
+            rules: vec![],
+            failed_per_minute: 5.0,
+            failed_burst: 5,
+            session_ttl_hours: 24,
+        }
+    }
+}
+
+/// All the settings for the server.
+///
+/// These are layered: defaults, then the TOML settings file, then the environment
+/// variables and finally the command line flags.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Address to listen on.
+    pub address: String,
+    /// Root of the public files.
+    pub source: PathBuf,
+    /// Root of the private files.
+    pub private_source: PathBuf,
+    /// JSON config of the private links.
+    pub config: PathBuf,
+    /// Filters for the logger (`env_logger` syntax).
+    pub log_level: String,
+    /// Token for the authenticated endpoints (uploads, admin, etc.)
+    pub admin_token: Option<String>,
+    /// Maximum size of a single uploaded file in bytes.
+    pub upload_size_limit: u64,
+    /// Directory for the resized images.
+    pub image_cache: PathBuf,
+    /// JSON file for the redirects of renamed files.
+    pub redirects: PathBuf,
+    /// Whether HTML, CSS and JS files should be minified.
+    pub minify: bool,
+    /// JSON manifest of the resources to be preloaded for pages.
+    pub preload_manifest: Option<PathBuf>,
+    /// Whether the resources to be preloaded should be discovered from pages.
+    pub preload_discover: bool,
+    /// Interval (in minutes) for sending the missing paths (if any, where 0 disables them).
+    pub missing_report_interval: Option<u64>,
+    /// Time (in seconds) for draining the in-flight requests on shutdown.
+    pub shutdown_timeout_secs: u64,
+    /// Proxies (IP addresses or CIDR ranges) whose forwarding headers are trusted.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Address to listen on for the metrics (disabled if unset).
+    pub metrics_address: Option<String>,
+    /// Status (403 or 404) for the clients denied by the access rules.
+    pub access_denied_status: u16,
+    /// Access rules for the path prefixes (the longest matching prefix applies).
+    pub access_control: Vec<AccessControlRule>,
+    pub watcher: WatcherSettings,
+    pub sms: SmsSettings,
+    pub tls: TlsSettings,
+    pub acme: AcmeSettings,
+    pub access_log: AccessLogSettings,
+    pub rate_limit: RateLimitSettings,
+    pub protect: ProtectSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            address: String::from("localhost:8000"),
+            source: PathBuf::from("./source"),
+            private_source: PathBuf::from("./private"),
+            config: PathBuf::from("config.json"),
+            log_level: String::from("info"),
+            admin_token: None,
+            upload_size_limit: 1 << 30,
+            image_cache: PathBuf::from("./cache/images"),
+            redirects: PathBuf::from("redirects.json"),
+            minify: false,
+            preload_manifest: None,
+            preload_discover: false,
+            missing_report_interval: None,
+            shutdown_timeout_secs: 8, // within the default grace period of `docker stop`
+            trusted_proxies: vec![],
+            metrics_address: None,
+            access_denied_status: 403,
+            access_control: vec![],
+            watcher: WatcherSettings::default(),
+            sms: SmsSettings::default(),
+            tls: TlsSettings::default(),
+            acme: AcmeSettings::default(),
+            access_log: AccessLogSettings::default(),
+            rate_limit: RateLimitSettings::default(),
+            protect: ProtectSettings::default(),
+        }
+    }
+}
+
+/// Static file server for waffles.space
+///
+/// All the flags can also be set through the environment variables (shown below),
+/// or in the settings file (with the flags in snake case, `watcher.*`, `sms.*`, `tls.*`,
+/// `acme.*`, `access_log.*`, `rate_limit.*` and `protect.*` flags in their own tables,
+/// the certificates in `[[tls.certificates]]`, the rate limits of the paths in
+/// `[[rate_limit.rules]]`, their access rules in `[[access_control]]`, and their
+/// passwords in `[protect.credentials.<name>]` and `[[protect.rules]]`).
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Settings file (TOML) [default: server.toml, if it exists]
+    #[arg(long, env = "SETTINGS")]
+    settings: Option<PathBuf>,
+    /// Address to listen on [default: localhost:8000]
+    #[arg(long, env = "ADDRESS")]
+    address: Option<String>,
+    /// Root of the public files [default: ./source]
+    #[arg(long, env = "SOURCE")]
+    source: Option<PathBuf>,
+    /// Root of the private files [default: ./private]
+    #[arg(long, env = "PRIVATE_SOURCE")]
+    private_source: Option<PathBuf>,
+    /// JSON config of the private links [default: config.json]
+    #[arg(long, env = "CONFIG")]
+    config: Option<PathBuf>,
+    /// Filters for the logger, e.g. `info,server=debug` [default: info]
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Token for the authenticated endpoints (disabled if unset)
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// Maximum size of a single uploaded file in bytes [default: 1 GiB]
+    #[arg(long, env = "UPLOAD_SIZE_LIMIT")]
+    upload_size_limit: Option<u64>,
+    /// Directory for the resized images [default: ./cache/images]
+    #[arg(long, env = "IMAGE_CACHE")]
+    image_cache: Option<PathBuf>,
+    /// JSON file for the redirects of renamed files [default: redirects.json]
+    #[arg(long, env = "REDIRECTS")]
+    redirects: Option<PathBuf>,
+    /// Minify HTML, CSS and JS files
+    #[arg(long, env = "MINIFY", value_parser = BoolishValueParser::new())]
+    minify: Option<bool>,
+    /// JSON manifest of the resources to be preloaded for pages
+    #[arg(long, env = "PRELOAD_MANIFEST")]
+    preload_manifest: Option<PathBuf>,
+    /// Discover the resources to be preloaded from pages
+    #[arg(long, env = "PRELOAD_DISCOVER", value_parser = BoolishValueParser::new())]
+    preload_discover: Option<bool>,
+    /// Interval (in minutes) for sending the missing paths
+    #[arg(long, env = "MISSING_REPORT_INTERVAL")]
+    missing_report_interval: Option<u64>,
+    /// Time (in seconds) for draining the in-flight requests on shutdown [default: 8]
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Proxies whose forwarding headers are trusted (comma-separated IPs or CIDR ranges)
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Option<Vec<Cidr>>,
+    /// Address to listen on for the metrics (`/metrics`, in Prometheus format)
+    #[arg(long, env = "METRICS_ADDRESS")]
+    metrics_address: Option<String>,
+    /// Status for the clients denied by the access rules (403 or 404) [default: 403]
+    #[arg(long, env = "ACCESS_DENIED_STATUS")]
+    access_denied_status: Option<u16>,
+    /// Interval for checking the private root and config [default: 1000]
+    #[arg(long, env = "WATCHER_SLEEP_DURATION_MS")]
+    watcher_sleep_duration_ms: Option<u64>,
+    /// Interval for sending the accesses of private paths [default: 300]
+    #[arg(long, env = "WATCHER_SMS_DURATION_SECS")]
+    watcher_sms_duration_secs: Option<u64>,
+    /// Receiver number for the messages (E.164 format)
+    #[arg(long, env = "SMS_RECEIVER")]
+    sms_receiver: Option<String>,
+    /// AWS region for sending the messages through SNS
+    #[arg(long, env = "AWS_REGION")]
+    sms_aws_region: Option<String>,
+    /// Maximum length of a single message [default: 140]
+    #[arg(long, env = "SMS_LIMIT")]
+    sms_limit: Option<usize>,
+    /// Address to listen on for HTTPS
+    #[arg(long, env = "TLS_ADDRESS")]
+    tls_address: Option<String>,
+    /// PEM file with the certificate chain (for all hosts)
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM file with the private key (for all hosts)
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Domains for obtaining a certificate through ACME (comma-separated)
+    #[arg(long, env = "ACME_DOMAINS", value_delimiter = ',')]
+    acme_domains: Option<Vec<String>>,
+    /// Directory URL of the ACME server [default: Let's Encrypt]
+    #[arg(long, env = "ACME_DIRECTORY")]
+    acme_directory: Option<String>,
+    /// Contact for the ACME account (e.g. `mailto:admin@example.com`)
+    #[arg(long, env = "ACME_CONTACT")]
+    acme_contact: Option<String>,
+    /// Directory for storing the ACME account key and certificate [default: ./acme]
+    #[arg(long, env = "ACME_DIR")]
+    acme_dir: Option<PathBuf>,
+    /// Additional CA certificate (PEM) for trusting the ACME server
+    #[arg(long, env = "ACME_CA_CERT")]
+    acme_ca_cert: Option<PathBuf>,
+    /// Days before expiry when the certificate should be renewed [default: 30]
+    #[arg(long, env = "ACME_RENEW_BEFORE_DAYS")]
+    acme_renew_before_days: Option<u64>,
+    /// Format of the access log [default: off]
+    #[arg(long, env = "ACCESS_LOG")]
+    access_log: Option<AccessLogFormat>,
+    /// File for the access log [default: stdout]
+    #[arg(long, env = "ACCESS_LOG_FILE")]
+    access_log_file: Option<PathBuf>,
+    /// Size (in MiB) at which the access log file is rotated [default: 100]
+    #[arg(long, env = "ACCESS_LOG_MAX_SIZE_MB")]
+    access_log_max_size_mb: Option<u64>,
+    /// Number of rotated access log files to keep [default: 5]
+    #[arg(long, env = "ACCESS_LOG_KEEP")]
+    access_log_keep: Option<usize>,
+    /// Requests per minute from each client with invalid or expired private tokens [default: 10]
+    #[arg(long, env = "RATE_LIMIT_INVALID_TOKEN_PER_MINUTE")]
+    rate_limit_invalid_token_per_minute: Option<f64>,
+    /// Requests at once from each client with invalid or expired private tokens [default: 10]
+    #[arg(long, env = "RATE_LIMIT_INVALID_TOKEN_BURST")]
+    rate_limit_invalid_token_burst: Option<u32>,
+    /// Failed password attempts per minute from each client [default: 5]
+    #[arg(long, env = "PROTECT_FAILED_PER_MINUTE")]
+    protect_failed_per_minute: Option<f64>,
+    /// Failed password attempts at once from each client [default: 5]
+    #[arg(long, env = "PROTECT_FAILED_BURST")]
+    protect_failed_burst: Option<u32>,
+    /// Hours for which the logins through the login page last [default: 24]
+    #[arg(long, env = "PROTECT_SESSION_TTL_HOURS")]
+    protect_session_ttl_hours: Option<u64>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check the HTML files in the public root for broken links and missing assets.
+    CheckLinks,
+    /// Check whether the server (on `address`) is live and ready, for container healthchecks.
+    Healthcheck,
+    /// Hash the password (read from stdin) for the credentials of the protected paths.
+    HashPassword,
+}
+
+/// Errors in loading the settings.
+#[derive(Debug)]
+pub enum SettingsError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(Vec<String>),
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::Read(path, e) => write!(f, "Cannot read {}: {}", path.display(), e),
+            SettingsError::Parse(path, e) => write!(f, "Cannot parse {}: {}", path.display(), e),
+            SettingsError::Invalid(errors) => {
+                write!(f, "Invalid settings:")?;
+                for e in errors {
+                    write!(f, "\n  - {}", e)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Settings {
+    /// Load the settings for the given command line arguments (and environment).
+    pub fn load(cli: &Cli) -> Result<Self, SettingsError> {
+        let (path, required) = match &cli.settings {
+            Some(p) => (p.clone(), true),
+            None => (PathBuf::from(DEFAULT_SETTINGS_FILE), false),
+        };
+
+        let mut settings = match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| SettingsError::Parse(path, e))?,
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(SettingsError::Read(path, e))
+            }
+            Err(_) => Settings::default(),
+        };
+
+        settings.apply(cli);
+        settings.validate().map_err(SettingsError::Invalid)?;
+        Ok(settings)
+    }
+
+    /// Override the settings with the flags (or environment variables).
+    fn apply(&mut self, cli: &Cli) {
+        fn set<T: Clone>(field: &mut T, value: &Option<T>) {
+            if let Some(v) = value {
+                *field = v.clone();
+            }
+        }
+
+        fn set_opt<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *field = value.clone();
+            }
+        }
+
+        set(&mut self.address, &cli.address);
+        set(&mut self.source, &cli.source);
+        set(&mut self.private_source, &cli.private_source);
+        set(&mut self.config, &cli.config);
+        set(&mut self.log_level, &cli.log_level);
+        set_opt(&mut self.admin_token, &cli.admin_token);
+        set(&mut self.upload_size_limit, &cli.upload_size_limit);
+        set(&mut self.image_cache, &cli.image_cache);
+        set(&mut self.redirects, &cli.redirects);
+        set(&mut self.minify, &cli.minify);
+        set_opt(&mut self.preload_manifest, &cli.preload_manifest);
+        set(&mut self.preload_discover, &cli.preload_discover);
+        set_opt(
+            &mut self.missing_report_interval,
+            &cli.missing_report_interval,
+        );
+        set(&mut self.shutdown_timeout_secs, &cli.shutdown_timeout_secs);
+        set(&mut self.trusted_proxies, &cli.trusted_proxies);
+        set_opt(&mut self.metrics_address, &cli.metrics_address);
+        set(&mut self.access_denied_status, &cli.access_denied_status);
+        set(
+            &mut self.watcher.sleep_duration_ms,
+            &cli.watcher_sleep_duration_ms,
+        );
+        set(
+            &mut self.watcher.sms_duration_secs,
+            &cli.watcher_sms_duration_secs,
+        );
+        set_opt(&mut self.sms.receiver, &cli.sms_receiver);
+        set_opt(&mut self.sms.aws_region, &cli.sms_aws_region);
+        set(&mut self.sms.limit, &cli.sms_limit);
+        set_opt(&mut self.tls.address, &cli.tls_address);
+        if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+            self.tls.certificates = vec![CertificateSettings {
+                hosts: vec![],
+                cert: cert.clone(),
+                key: key.clone(),
+            }];
+        }
+
+        set(&mut self.acme.domains, &cli.acme_domains);
+        set(&mut self.acme.directory, &cli.acme_directory);
+        set_opt(&mut self.acme.contact, &cli.acme_contact);
+        set(&mut self.acme.dir, &cli.acme_dir);
+        set_opt(&mut self.acme.ca_cert, &cli.acme_ca_cert);
+        set(
+            &mut self.acme.renew_before_days,
+            &cli.acme_renew_before_days,
+        );
+        set(&mut self.access_log.format, &cli.access_log);
+        set_opt(&mut self.access_log.file, &cli.access_log_file);
+        set(
+            &mut self.access_log.max_size_mb,
+            &cli.access_log_max_size_mb,
+        );
+        set(&mut self.access_log.keep, &cli.access_log_keep);
+        set(
+            &mut self.rate_limit.invalid_token_per_minute,
+            &cli.rate_limit_invalid_token_per_minute,
+        );
+        set(
+            &mut self.rate_limit.invalid_token_burst,
+            &cli.rate_limit_invalid_token_burst,
+        );
+        set(
+            &mut self.protect.failed_per_minute,
+            &cli.protect_failed_per_minute,
+        );
+        set(&mut self.protect.failed_burst, &cli.protect_failed_burst);
+        set(
+            &mut self.protect.session_ttl_hours,
+            &cli.protect_session_ttl_hours,
+        );
+
+        // Empty (or zero) values disable the optional features (as they did with env vars).
+        self.admin_token = self.admin_token.take().filter(|s| !s.is_empty());
+        self.sms.receiver = self.sms.receiver.take().filter(|s| !s.is_empty());
+        self.sms.aws_region = self.sms.aws_region.take().filter(|s| !s.is_empty());
+        self.tls.address = self.tls.address.take().filter(|s| !s.is_empty());
+        self.metrics_address = self.metrics_address.take().filter(|s| !s.is_empty());
+        self.missing_report_interval = self.missing_report_interval.filter(|&m| m > 0);
+        self.acme.domains.retain(|d| !d.is_empty());
+    }
+
+    /// Check the settings and collect all the problems (if any).
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+        let resolves = |a: &str| a.to_socket_addrs().map(|mut a| a.next().is_some());
+        if !resolves(&self.address).unwrap_or(false) {
+            errors.push(format!(
+                "address: {:?} is not a valid address",
+                self.address
+            ));
+        }
+
+        if let Some(address) = &self.metrics_address {
+            if !resolves(address).unwrap_or(false) {
+                errors.push(format!(
+                    "metrics_address: {:?} is not a valid address",
+                    address
+                ));
+            }
+        }
+
+        if ![403, 404].contains(&self.access_denied_status) {
+            errors.push(format!(
+                "access_denied_status: {} should be 403 or 404",
+                self.access_denied_status
+            ));
+        }
+
+        for rule in &self.access_control {
+            if !rule.prefix.starts_with('/') {
+                errors.push(format!(
+                    "access_control: prefix {:?} should start with /",
+                    rule.prefix
+                ));
+            }
+        }
+
+        let not_dir = |p: &Path| p.exists() && !p.is_dir();
+        for (name, path) in [
+            ("source", &self.source),
+            ("private_source", &self.private_source),
+            ("image_cache", &self.image_cache),
+        ] {
+            if not_dir(path) {
+                errors.push(format!("{}: {} is not a directory", name, path.display()));
+            }
+        }
+
+        for directive in self.log_level.split(',').filter(|d| !d.is_empty()) {
+            if let Some((_, level)) = directive.split_once('=') {
+                if level.parse::<LevelFilter>().is_err() {
+                    errors.push(format!("log_level: {:?} is not a valid level", level));
+                }
+            }
+        }
+
+        if self.upload_size_limit == 0 {
+            errors.push(String::from("upload_size_limit: should be positive"));
+        }
+
+        match &self.preload_manifest {
+            Some(p) if !p.is_file() => {
+                errors.push(format!("preload_manifest: {} doesn't exist", p.display()))
+            }
+            _ => (),
+        }
+
+        if self.watcher.sleep_duration_ms == 0 {
+            errors.push(String::from(
+                "watcher.sleep_duration_ms: should be positive",
+            ));
+        }
+
+        if self.watcher.sms_duration_secs == 0 {
+            errors.push(String::from(
+                "watcher.sms_duration_secs: should be positive",
+            ));
+        }
+
+        if !(10..=1600).contains(&self.sms.limit) {
+            errors.push(format!(
+                "sms.limit: {} should be between 10 and 1600",
+                self.sms.limit
+            ));
+        }
+
+        if let Some(r) = &self.sms.receiver {
+            let digits = r.strip_prefix('+').unwrap_or("");
+            if !(8..=15).contains(&digits.len()) || !digits.chars().all(|c| c.is_ascii_digit()) {
+                errors.push(format!("sms.receiver: {:?} is not in E.164 format", r));
+            }
+        }
+
+        if let Some(r) = &self.sms.aws_region {
+            if r.parse::<Region>().is_err() {
+                errors.push(format!("sms.aws_region: {:?} is not a valid region", r));
+            }
+        }
+
+        if let Some(address) = &self.tls.address {
+            if !resolves(address).unwrap_or(false) {
+                errors.push(format!("tls.address: {:?} is not a valid address", address));
+            }
+
+            if self.tls.certificates.is_empty() && !self.acme.is_enabled() {
+                errors.push(String::from("tls.certificates: should have at least one"));
+            }
+        }
+
+        for (i, c) in self.tls.certificates.iter().enumerate() {
+            for (name, path) in [("cert", &c.cert), ("key", &c.key)] {
+                if !path.is_file() {
+                    errors.push(format!(
+                        "tls.certificates[{}].{}: {} doesn't exist",
+                        i,
+                        name,
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        if self.acme.is_enabled() {
+            if self.tls.address.is_none() {
+                errors.push(String::from("acme.domains: tls.address is needed for ACME"));
+            }
+
+            if !self.acme.directory.starts_with("https://") {
+                errors.push(format!(
+                    "acme.directory: {:?} should be an HTTPS URL",
+                    self.acme.directory
+                ));
+            }
+
+            if not_dir(&self.acme.dir) {
+                errors.push(format!(
+                    "acme.dir: {} is not a directory",
+                    self.acme.dir.display()
+                ));
+            }
+
+            match &self.acme.ca_cert {
+                Some(p) if !p.is_file() => {
+                    errors.push(format!("acme.ca_cert: {} doesn't exist", p.display()))
+                }
+                _ => (),
+            }
+
+            if self.acme.renew_before_days == 0 {
+                errors.push(String::from("acme.renew_before_days: should be positive"));
+            }
+        }
+
+        if let Some(file) = &self.access_log.file {
+            if file.is_dir() {
+                errors.push(format!(
+                    "access_log.file: {} is a directory",
+                    file.display()
+                ));
+            }
+
+            if self.access_log.max_size_mb == 0 {
+                errors.push(String::from("access_log.max_size_mb: should be positive"));
+            }
+        }
+
+        // NaN isn't positive either.
+        let positive = |rate: f64| rate > 0.0 && rate.is_finite();
+        for rule in &self.rate_limit.rules {
+            if !rule.prefix.starts_with('/') {
+                errors.push(format!(
+                    "rate_limit.rules: prefix {:?} should start with /",
+                    rule.prefix
+                ));
+            }
+
+            if !positive(rule.per_minute) || rule.burst == 0 {
+                errors.push(format!(
+                    "rate_limit.rules: per_minute and burst for {:?} should be positive",
+                    rule.prefix
+                ));
+            }
+        }
+
+        if !positive(self.rate_limit.invalid_token_per_minute) {
+            errors.push(String::from(
+                "rate_limit.invalid_token_per_minute: should be positive",
+            ));
+        }
+
+        if self.rate_limit.invalid_token_burst == 0 {
+            errors.push(String::from(
+                "rate_limit.invalid_token_burst: should be positive",
+            ));
+        }
+
+        for (name, users) in &self.protect.credentials {
+            for (user, hash) in users {
+                let is_argon2 = PasswordHash::new(hash)
+                    .is_ok_and(|h| h.algorithm.as_str().starts_with("argon2"));
+                if !is_argon2 {
+                    errors.push(format!(
+                        "protect.credentials.{}: hash for {:?} is not an argon2 hash",
+                        name, user
+                    ));
+                }
+            }
+        }
+
+        for rule in &self.protect.rules {
+            if !self.protect.credentials.contains_key(&rule.credentials) {
+                errors.push(format!(
+                    "protect.rules: credentials {:?} don't exist",
+                    rule.credentials
+                ));
+            }
+
+            if rule.paths.is_empty() {
+                errors.push(format!(
+                    "protect.rules: paths for {:?} should have at least one",
+                    rule.credentials
+                ));
+            }
+
+            for path in &rule.paths {
+                if let Err(e) = Glob::new(path) {
+                    errors.push(format!("protect.rules: {}", e));
+                } else if !path.starts_with('/') {
+                    errors.push(format!(
+                        "protect.rules: path {:?} should start with /",
+                        path
+                    ));
+                }
+            }
+        }
+
+        if !positive(self.protect.failed_per_minute) {
+            errors.push(String::from(
+                "protect.failed_per_minute: should be positive",
+            ));
+        }
+
+        if self.protect.failed_burst == 0 {
+            errors.push(String::from("protect.failed_burst: should be positive"));
+        }
+
+        if self.protect.session_ttl_hours == 0 {
+            errors.push(String::from(
+                "protect.session_ttl_hours: should be positive",
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Directory (in the public root) where the private links are served from.
+    pub fn private_serve_path(&self) -> PathBuf {
+        self.source
+            .join(crate::server::PRIVATE_PATH_PREFIX.trim_start_matches('/'))
+    }
+}
+
+/// Set the settings for the rest of the server.
+pub fn init(settings: Settings) {
+    SETTINGS
+        .set(settings)
+        .expect("settings already initialized");
+}
+
+/// Get the settings (once they've been initialized).
+pub fn get() -> &'static Settings {
+    SETTINGS.get().expect("settings not initialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AccessControlRule, CertificateSettings, Cli, ProtectRule, RateLimitRule, Settings,
+    };
+    use clap::Parser;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Setting which makes `validate` fail with the message.
+    type Case = (fn(&mut Settings), &'static str);
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("server").chain(args.iter().copied()))
+            .expect("valid flags")
+    }
+
+    #[test]
+    fn test_precedence() {
+        let path = std::env::temp_dir().join(format!("settings-test-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "address = \"127.0.0.1:1\"\n\
+             log_level = \"warn\"\n\
+             admin_token = \"file\"\n\
+             shutdown_timeout_secs = 3\n\
+             [watcher]\n\
+             sleep_duration_ms = 50\n",
+        )
+        .expect("settings file");
+
+        std::env::set_var("ADDRESS", "127.0.0.1:2");
+        std::env::set_var("LOG_LEVEL", "debug");
+        std::env::set_var("ADMIN_TOKEN", "env");
+        let cli = cli(&[
+            "--settings",
+            path.to_str().expect("UTF-8 path"),
+            "--address",
+            "127.0.0.1:3",
+            "--watcher-sleep-duration-ms",
+            "70",
+        ]);
+        std::env::remove_var("ADDRESS");
+        std::env::remove_var("LOG_LEVEL");
+        std::env::remove_var("ADMIN_TOKEN");
+
+        let settings = Settings::load(&cli);
+        fs::remove_file(&path).expect("settings file removed");
+        let settings = settings.expect("valid settings");
+
+        // file < env < flag
+        assert_eq!(settings.address, "127.0.0.1:3");
+        assert_eq!(settings.watcher.sleep_duration_ms, 70);
+        assert_eq!(settings.log_level, "debug");
+        assert_eq!(settings.admin_token.as_deref(), Some("env"));
+        assert_eq!(settings.shutdown_timeout_secs, 3);
+        // defaults
+        assert_eq!(settings.access_denied_status, 403);
+        assert_eq!(settings.watcher.sms_duration_secs, 300);
+    }
+
+    #[test]
+    fn test_empty_values_disable() {
+        let file = "admin_token = \"\"\n\
+                    metrics_address = \"\"\n\
+                    missing_report_interval = 0\n\
+                    [sms]\n\
+                    receiver = \"\"\n\
+                    aws_region = \"\"\n\
+                    [tls]\n\
+                    address = \"\"\n\
+                    [acme]\n\
+                    domains = [\"\", \"example.com\"]\n";
+        let mut from_file: Settings = toml::from_str(file).expect("valid TOML");
+        from_file.apply(&cli(&[]));
+
+        // The flags (or environment variables) also disable what the file enables.
+        let file = "admin_token = \"x\"\n\
+                    metrics_address = \"127.0.0.1:9000\"\n\
+                    missing_report_interval = 5\n\
+                    [sms]\n\
+                    receiver = \"+15550100\"\n\
+                    aws_region = \"eu-west-1\"\n\
+                    [tls]\n\
+                    address = \"127.0.0.1:8443\"\n";
+        let mut from_flags: Settings = toml::from_str(file).expect("valid TOML");
+        from_flags.apply(&cli(&[
+            "--admin-token=",
+            "--metrics-address=",
+            "--missing-report-interval=0",
+            "--sms-receiver=",
+            "--sms-aws-region=",
+            "--tls-address=",
+            "--acme-domains=,example.com,",
+        ]));
+
+        for settings in [from_file, from_flags] {
+            assert_eq!(settings.admin_token, None);
+            assert_eq!(settings.metrics_address, None);
+            assert_eq!(settings.missing_report_interval, None);
+            assert_eq!(settings.sms.receiver, None);
+            assert_eq!(settings.sms.aws_region, None);
+            assert_eq!(settings.tls.address, None);
+            assert_eq!(settings.acme.domains, vec!["example.com"]);
+        }
+
+        let mut settings = Settings::default();
+        settings.apply(&cli(&["--missing-report-interval=5"]));
+        assert_eq!(settings.missing_report_interval, Some(5));
+    }
+
+    #[test]
+    fn test_validate() {
+        // The tests run in the package root.
+        let cases: &[Case] = &[
+            (
+                |s| s.address = "nope".into(),
+                "address: \"nope\" is not a valid address",
+            ),
+            (
+                |s| s.metrics_address = Some("nope".into()),
+                "metrics_address: \"nope\" is not a valid address",
+            ),
+            (
+                |s| s.access_denied_status = 401,
+                "access_denied_status: 401 should be 403 or 404",
+            ),
+            (
+                |s| {
+                    s.access_control = vec![AccessControlRule {
+                        prefix: "admin".into(),
+                        rules: vec![],
+                    }]
+                },
+                "access_control: prefix \"admin\" should start with /",
+            ),
+            (
+                |s| s.source = "Cargo.toml".into(),
+                "source: Cargo.toml is not a directory",
+            ),
+            (
+                |s| s.private_source = "Cargo.toml".into(),
+                "private_source: Cargo.toml is not a directory",
+            ),
+            (
+                |s| s.image_cache = "Cargo.toml".into(),
+                "image_cache: Cargo.toml is not a directory",
+            ),
+            (
+                |s| s.log_level = "info,server=loud".into(),
+                "log_level: \"loud\" is not a valid level",
+            ),
+            (
+                |s| s.upload_size_limit = 0,
+                "upload_size_limit: should be positive",
+            ),
+            (
+                |s| s.preload_manifest = Some("missing.json".into()),
+                "preload_manifest: missing.json doesn't exist",
+            ),
+            (
+                |s| s.watcher.sleep_duration_ms = 0,
+                "watcher.sleep_duration_ms: should be positive",
+            ),
+            (
+                |s| s.watcher.sms_duration_secs = 0,
+                "watcher.sms_duration_secs: should be positive",
+            ),
+            (
+                |s| s.sms.limit = 5,
+                "sms.limit: 5 should be between 10 and 1600",
+            ),
+            (
+                |s| s.sms.receiver = Some("5550100".into()),
+                "sms.receiver: \"5550100\" is not in E.164 format",
+            ),
+            (
+                |s| s.sms.receiver = Some("+1555-0100".into()),
+                "sms.receiver: \"+1555-0100\" is not in E.164 format",
+            ),
+            (
+                |s| s.sms.aws_region = Some("mars-1".into()),
+                "sms.aws_region: \"mars-1\" is not a valid region",
+            ),
+            (
+                |s| {
+                    s.tls.address = Some("nope".into());
+                    s.tls.certificates = vec![certificate("Cargo.toml", "Cargo.toml")];
+                },
+                "tls.address: \"nope\" is not a valid address",
+            ),
+            (
+                |s| s.tls.address = Some("127.0.0.1:8443".into()),
+                "tls.certificates: should have at least one",
+            ),
+            (
+                |s| s.tls.certificates = vec![certificate("missing.pem", "Cargo.toml")],
+                "tls.certificates[0].cert: missing.pem doesn't exist",
+            ),
+            (
+                |s| {
+                    s.tls.certificates = vec![
+                        certificate("Cargo.toml", "Cargo.toml"),
+                        certificate("Cargo.toml", "missing.pem"),
+                    ]
+                },
+                "tls.certificates[1].key: missing.pem doesn't exist",
+            ),
+            (
+                |s| s.acme.domains = vec!["example.com".into()],
+                "acme.domains: tls.address is needed for ACME",
+            ),
+            (
+                |s| {
+                    acme(s);
+                    s.acme.directory = "http://localhost:14000/dir".into();
+                },
+                "acme.directory: \"http://localhost:14000/dir\" should be an HTTPS URL",
+            ),
+            (
+                |s| {
+                    acme(s);
+                    s.acme.dir = "Cargo.toml".into();
+                },
+                "acme.dir: Cargo.toml is not a directory",
+            ),
+            (
+                |s| {
+                    acme(s);
+                    s.acme.ca_cert = Some("missing.pem".into());
+                },
+                "acme.ca_cert: missing.pem doesn't exist",
+            ),
+            (
+                |s| {
+                    acme(s);
+                    s.acme.renew_before_days = 0;
+                },
+                "acme.renew_before_days: should be positive",
+            ),
+            (
+                |s| s.access_log.file = Some("src".into()),
+                "access_log.file: src is a directory",
+            ),
+            (
+                |s| {
+                    s.access_log.file = Some("access.log".into());
+                    s.access_log.max_size_mb = 0;
+                },
+                "access_log.max_size_mb: should be positive",
+            ),
+            (
+                |s| s.rate_limit.rules = vec![rate_limit("api", 60.0, 10)],
+                "rate_limit.rules: prefix \"api\" should start with /",
+            ),
+            (
+                |s| s.rate_limit.rules = vec![rate_limit("/api", f64::NAN, 10)],
+                "rate_limit.rules: per_minute and burst for \"/api\" should be positive",
+            ),
+            (
+                |s| s.rate_limit.rules = vec![rate_limit("/api", 60.0, 0)],
+                "rate_limit.rules: per_minute and burst for \"/api\" should be positive",
+            ),
+            (
+                |s| s.rate_limit.invalid_token_per_minute = f64::INFINITY,
+                "rate_limit.invalid_token_per_minute: should be positive",
+            ),
+            (
+                |s| s.rate_limit.invalid_token_burst = 0,
+                "rate_limit.invalid_token_burst: should be positive",
+            ),
+            (
+                |s| {
+                    s.protect
+                        .credentials
+                        .insert("family".into(), [("ravi".into(), "hunter2".into())].into());
+                },
+                "protect.credentials.family: hash for \"ravi\" is not an argon2 hash",
+            ),
+            (
+                |s| s.protect.rules = vec![protect_rule("family", &["/photos"])],
+                "protect.rules: credentials \"family\" don't exist",
+            ),
+            (
+                |s| {
+                    s.protect.credentials.insert("family".into(), HashMap::new());
+                    s.protect.rules = vec![protect_rule("family", &[])];
+                },
+                "protect.rules: paths for \"family\" should have at least one",
+            ),
+            (
+                |s| {
+                    s.protect.credentials.insert("family".into(), HashMap::new());
+                    s.protect.rules = vec![protect_rule("family", &["/photos/[a"])];
+                },
+                "protect.rules: error parsing glob '/photos/[a': unclosed character class; missing ']'",
+            ),
+            (
+                |s| {
+                    s.protect.credentials.insert("family".into(), HashMap::new());
+                    s.protect.rules = vec![protect_rule("family", &["photos/**"])];
+                },
+                "protect.rules: path \"photos/**\" should start with /",
+            ),
+            (
+                |s| s.protect.failed_per_minute = 0.0,
+                "protect.failed_per_minute: should be positive",
+            ),
+            (
+                |s| s.protect.failed_burst = 0,
+                "protect.failed_burst: should be positive",
+            ),
+            (
+                |s| s.protect.session_ttl_hours = 0,
+                "protect.session_ttl_hours: should be positive",
+            ),
+        ];
+
+        let valid = || Settings {
+            address: "127.0.0.1:8000".into(),
+            ..Default::default()
+        };
+        assert_eq!(valid().validate(), Ok(()));
+
+        for (set, expected) in cases {
+            let mut settings = valid();
+            set(&mut settings);
+            assert_eq!(
+                settings.validate(),
+                Err(vec![expected.to_string()]),
+                "validating for {:?}",
+                expected
+            );
+        }
+    }
+
+    fn certificate(cert: &str, key: &str) -> CertificateSettings {
+        CertificateSettings {
+            hosts: vec![],
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+        }
+    }
+
+    /// Enable ACME (with everything else valid).
+    fn acme(settings: &mut Settings) {
+        settings.tls.address = Some("127.0.0.1:8443".into());
+        settings.acme.domains = vec!["example.com".into()];
+    }
+
+    fn rate_limit(prefix: &str, per_minute: f64, burst: u32) -> RateLimitRule {
+        RateLimitRule {
+            prefix: prefix.into(),
+            per_minute,
+            burst,
+        }
+    }
+
+    fn protect_rule(credentials: &str, paths: &[&str]) -> ProtectRule {
+        ProtectRule {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            credentials: credentials.into(),
+            mode: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::util;
+use async_std::task;
+use futures::channel::oneshot;
+use http_types::headers::CONNECTION;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tide::{Middleware, Next, Request};
+
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait for the first SIGTERM or SIGINT (a second one exits immediately).
+pub fn signal() -> oneshot::Receiver<()> {
+    let (tx, rx) = oneshot::channel();
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("registering signal handlers");
+    thread::spawn(move || {
+        let mut tx = Some(tx);
+        for signal in signals.forever() {
+            match tx.take() {
+                Some(tx) => {
+                    info!("Received signal {}, shutting down.", signal);
+                    let _ = tx.send(());
+                }
+                None => {
+                    warn!("Received signal {} again, exiting now.", signal);
+                    process::exit(1);
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+/// Decrements the count of in-flight requests when it's dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware which tracks the in-flight requests (including the streaming of
+/// their responses), so that they can be drained on shutdown.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    in_flight: Arc<AtomicUsize>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Whether the server is shutting down (and draining the requests).
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the in-flight requests to finish (up to the timeout), and return
+    /// whether all of them have finished.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.stopping.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        loop {
+            let count = self.in_flight.load(Ordering::SeqCst);
+            if count == 0 {
+                return true;
+            }
+
+            if start.elapsed() >= timeout {
+                warn!("Dropping {} in-flight requests.", count);
+                return false;
+            }
+
+            task::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Shutdown {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let in_flight = InFlight::new(&self.in_flight);
+        let mut resp = next.run(req).await;
+        if self.stopping.load(Ordering::SeqCst) {
+            // Keep-alive connections shouldn't bring more requests.
+            resp.insert_header(CONNECTION, "close");
+        }
+
+        if resp.is_empty() == Some(true) {
+            return Ok(resp);
+        }
+
+        // Keep the request in flight until its response has been streamed.
+        util::observe_body(&mut resp, move |_| drop(in_flight));
+        Ok(resp)
+    }
+}
@@ -2,21 +2,19 @@ use rusoto_core::{request::HttpClient, Region, RusotoError};
 use rusoto_credential::EnvironmentProvider;
 use rusoto_sns::{MessageAttributeValue, PublishError, PublishInput, Sns, SnsClient};
 
+use crate::metrics;
+use crate::settings;
+
 use std::collections::HashMap;
-use std::env;
 
 lazy_static! {
-    /// SMS receiver number (E.164 format).
-    static ref SMS_RECEIVER: Option<String>
-        = env::var("SMS_RECEIVER").ok();
-
     /* AWS */
 
     // Uses `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
     static ref AWS_ENV: EnvironmentProvider = EnvironmentProvider::default();
     /// AWS region used by the provider.
     static ref AWS_REGION: Option<Region>
-        = env::var("AWS_REGION").ok().and_then(|s| s.parse().ok());
+        = settings::get().sms.aws_region.as_ref().and_then(|s| s.parse().ok());
     static ref AWS_CLIENT: Option<SnsClient> = AWS_REGION.as_ref().map(|region| {
         SnsClient::new_with(HttpClient::new().expect("creating https client"), AWS_ENV.clone(), region.clone())
     });
@@ -32,17 +30,41 @@ lazy_static! {
     };
 }
 
+/// Builds a message with the title and as many lines as would fit in a single SMS.
+pub fn summarize(title: &str, lines: impl Iterator<Item = String>) -> String {
+    let limit = settings::get().sms.limit;
+    let mut msg = String::from(title);
+    for line in lines {
+        if msg.len() + line.len() + 1 > (limit - 4) {
+            msg.push_str("\n..."); // trim after single message length
+            break;
+        }
+
+        msg.push('\n');
+        msg.push_str(&line);
+    }
+
+    msg
+}
+
 /// Sends the message to the given number.
 pub async fn send(message: &str) {
     info!("[MESSAGE]\n{}\n", message);
-    if SMS_RECEIVER.is_none() {
+    if settings::get().sms.receiver.is_none() {
         return;
     }
 
     match send_using_aws(message).await {
-        Ok(true) => return,
+        Ok(true) => {
+            metrics::notification(true);
+            return;
+        }
         Ok(false) => (),
-        Err(e) => error!("Error sending message using AWS: {:?}", e),
+        Err(e) => {
+            // Only the attempts with a provider count as failures.
+            metrics::notification(false);
+            error!("Error sending message using AWS: {:?}", e);
+        }
     }
 
     error!("No supported SMS providers have been configured.");
@@ -51,10 +73,10 @@ pub async fn send(message: &str) {
 /// Sends a message using AWS SNS API.
 async fn send_using_aws(message: &str) -> Result<bool, RusotoError<PublishError>> {
     info!("Sending message using AWS.");
-    let (client, receiver) = match (AWS_CLIENT.as_ref(), SMS_RECEIVER.as_ref()) {
+    let (client, receiver) = match (AWS_CLIENT.as_ref(), settings::get().sms.receiver.as_ref()) {
         (Some(c), Some(r)) => (c, r),
         _ => {
-            info!("Missing environment variables for AWS API.");
+            info!("Missing settings for AWS API.");
             return Ok(false);
         }
     };
@@ -65,7 +87,8 @@ async fn send_using_aws(message: &str) -> Result<bool, RusotoError<PublishError>
             phone_number: Some(receiver.clone()),
             message_attributes: AWS_MSG_ATTRS.clone(),
             ..Default::default()
-        }).await?;
+        })
+        .await?;
     info!("AWS response: {:?}", resp);
     Ok(true)
 }
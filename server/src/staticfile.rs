@@ -4,21 +4,49 @@ use futures::future::{BoxFuture, FutureExt};
 use http::header;
 use httpdate::HttpDate;
 use tide::{Body, Request, Response, ResponseBuilder, StatusCode};
+use uuid::Uuid;
+
+use crate::metrics;
+use crate::minify::{self, Minifier};
+use crate::missing::MissingPaths;
+use crate::preload::Preloader;
+use crate::resolver::{self, ResolveError};
+use crate::util;
 
 use std::io;
-use std::path::{Component, Path, PathBuf};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::time::UNIX_EPOCH;
 
 const DEFAULT_4XX_BODY: &[u8] = b"Oops! I can't find what you're looking for..." as &[_];
 const DEFAULT_5XX_BODY: &[u8] = b"I'm broken, apparently." as &[_];
+/// Names of the custom error pages in the root.
+const ERROR_PAGES: [&str; 2] = ["4xx.html", "5xx.html"];
+
+/// What gets served for a path.
+pub enum Lookup {
+    /// Serve the file at this path.
+    File(PathBuf, Metadata),
+    /// Redirect to this location.
+    Redirect(String),
+    NotFound,
+}
+
+/// Bodies for the error responses.
+struct ErrorPages {
+    body_4xx: Vec<u8>,
+    body_5xx: Vec<u8>,
+}
 
 /// Simple static file handler for Tide.
 #[derive(Clone)]
 pub struct StaticFile {
-    // FIXME: These should be setters which also determine and set the MIME type.
-    pub body_4xx: Vec<u8>,
-    pub body_5xx: Vec<u8>,
+    // FIXME: The MIME type should be determined from the error pages.
+    error_pages: Arc<RwLock<ErrorPages>>,
     root: PathBuf,
+    minifier: Option<Minifier>,
+    preloader: Option<Preloader>,
+    missing: Option<MissingPaths>,
 }
 
 impl StaticFile {
@@ -31,143 +59,282 @@ impl StaticFile {
 
         StaticFile {
             root,
-            body_4xx: Vec::from(DEFAULT_4XX_BODY),
-            body_5xx: Vec::from(DEFAULT_5XX_BODY),
+            error_pages: Arc::new(RwLock::new(ErrorPages {
+                body_4xx: Vec::from(DEFAULT_4XX_BODY),
+                body_5xx: Vec::from(DEFAULT_5XX_BODY),
+            })),
+            minifier: None,
+            preloader: None,
+            missing: None,
         }
     }
 
-    /// Percent-decode, normalize path components and return the final path joined with root.
-    fn get_path(&self, path: &str) -> PathBuf {
-        let rel_path = Path::new(path)
-            .components()
-            .fold(PathBuf::new(), |mut result, p| {
-                match p {
-                    Component::Normal(x) => result.push({
-                        let s = x.to_str().unwrap_or("");
-                        &*percent_encoding::percent_decode(s.as_bytes()).decode_utf8_lossy()
-                    }),
-                    Component::ParentDir => {
-                        result.pop();
+    /// (Re)load the custom error pages from the root (if they exist).
+    ///
+    /// The pages are shared by all the handlers created from this one.
+    pub fn load_error_pages(&self) {
+        let load = |name: &str, default: &[u8]| {
+            let path = self.root.join(name);
+            if path.exists() {
+                match util::read_file(&path) {
+                    Ok(bytes) => {
+                        info!("Using custom {}", name);
+                        return bytes;
                     }
-                    _ => (),
+                    Err(e) => error!("Cannot read {}: {}", path.display(), e),
                 }
+            }
 
-                result
-            });
+            Vec::from(default)
+        };
+
+        let (body_4xx, body_5xx) = (
+            load(ERROR_PAGES[0], DEFAULT_4XX_BODY),
+            load(ERROR_PAGES[1], DEFAULT_5XX_BODY),
+        );
+        *self.error_pages.write().expect("error pages lock poisoned") =
+            ErrorPages { body_4xx, body_5xx };
+    }
+
+    /// Whether the path (relative to root) is one of the custom error pages.
+    pub fn is_error_page(rel_path: &Path) -> bool {
+        ERROR_PAGES.iter().any(|p| rel_path == Path::new(p))
+    }
+
+    /// Enable (or disable) minifying HTML, CSS and JS files.
+    pub fn set_minifier(&mut self, minifier: Option<Minifier>) {
+        self.minifier = minifier;
+    }
+
+    /// Enable (or disable) the preload headers for HTML pages.
+    pub fn set_preloader(&mut self, preloader: Option<Preloader>) {
+        self.preloader = preloader;
+    }
+
+    /// Enable (or disable) tracking the paths which were responded with 404.
+    pub fn set_missing_paths(&mut self, missing: Option<MissingPaths>) {
+        self.missing = missing;
+    }
+
+    /// Creates a handler for another root, which shares the error pages of this handler.
+    pub fn with_root(&self, root: impl AsRef<Path>) -> Self {
+        StaticFile {
+            root: PathBuf::from(root.as_ref()),
+            ..self.clone()
+        }
+    }
+
+    /// Root of this handler.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
 
-        self.root.join(rel_path)
+    /// Resolve the request path into a path inside root (see `resolver::resolve`).
+    pub fn get_path(&self, path: &str) -> Result<PathBuf, ResolveError> {
+        resolver::resolve(&self.root, path)
+    }
+
+    /// Find what should be served for the given (resolved) path and the URL path it came from.
+    pub async fn lookup(&self, path: PathBuf, url_path: &str) -> io::Result<Lookup> {
+        let meta = match fs::metadata(&path).await {
+            Ok(m) => m,
+            // Missing paths are served the 404 page, everything else gets classified.
+            Err(ref e) if status_for_error(e) == StatusCode::NotFound => {
+                return Ok(Lookup::NotFound)
+            }
+            Err(e) => return Err(e),
+        };
+
+        if meta.is_file() {
+            return Ok(Lookup::File(path, meta));
+        }
+
+        // Redirect if path is a dir and URL doesn't end with "/"
+        if !url_path.ends_with('/') {
+            return Ok(Lookup::Redirect(String::from(url_path) + "/"));
+        }
+
+        // Directories are served using their `index.html` (if any).
+        let index = path.join("index.html");
+        match fs::metadata(&index).await {
+            Ok(m) if m.is_file() => Ok(Lookup::File(index, m)),
+            Ok(_) => Ok(Lookup::NotFound),
+            Err(ref e) if status_for_error(e) == StatusCode::NotFound => Ok(Lookup::NotFound),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Build a response for the given error status using the custom error pages.
+    fn error_response(&self, status: StatusCode, request_id: &str) -> Response {
+        let pages = self.error_pages.read().expect("error pages lock poisoned");
+        let body = if status.is_server_error() {
+            &pages.body_5xx
+        } else {
+            &pages.body_4xx
+        };
+
+        let mut resp = Response::builder(status)
+            .body(body.clone())
+            .header(header::CONTENT_DISPOSITION.as_str(), "inline")
+            .header(header::CONTENT_LENGTH.as_str(), body.len().to_string())
+            .header("X-Request-Id", request_id)
+            .build();
+        resp.set_content_type(http_types::mime::HTML);
+        resp
+    }
+}
+
+/// Entity tag for a file (based on its modified time and size).
+pub fn etag(meta: &Metadata) -> io::Result<String> {
+    Ok(format!(
+        "{:x}-{:x}",
+        meta.modified()?
+            .duration_since(UNIX_EPOCH)
+            .expect("unix epoch is wrong?")
+            .as_secs(),
+        meta.len()
+    ))
+}
+
+/// Classify a filesystem error into the status code we should respond with.
+pub fn status_for_error(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => StatusCode::NotFound,
+        io::ErrorKind::InvalidFilename | io::ErrorKind::InvalidInput => StatusCode::BadRequest,
+        _ => StatusCode::InternalServerError,
     }
 }
 
 /// Responder to serve a file request.
 pub struct Responder<'a> {
+    request_id: String,
     actual_path: &'a str,
     state: &'a StaticFile,
-    path: PathBuf,
+    path: Result<PathBuf, ResolveError>,
     resp: ResponseBuilder,
     if_modified_since: Option<&'a str>,
     if_none_match: Option<&'a str>,
+    referrer: Option<&'a str>,
+    /// Whether the client is fine with minified files.
+    minify: bool,
 }
 
 impl<'a> Responder<'a> {
     /// Create an instance from an incoming request.
     pub fn from(req: &'a Request<StaticFile>) -> Self {
-        let actual_path = req.url().path();
-        let state = req.state();
+        Self::new(req, req.state(), req.url().path())
+    }
+
+    /// Create an instance for serving the path (relative to the root of the given handler).
+    pub fn new<State>(req: &'a Request<State>, state: &'a StaticFile, path: &str) -> Self {
         Responder {
-            actual_path,
-            state: &state,
-            path: state.get_path(actual_path),
+            request_id: Uuid::new_v4().simple().to_string(),
+            actual_path: req.url().path(),
+            state,
+            path: state.get_path(path),
             resp: Response::builder(200),
-            if_none_match: req.header(header::IF_NONE_MATCH.as_str()).map(|s| s.as_str()),
-            if_modified_since: req.header(header::IF_MODIFIED_SINCE.as_str()).map(|s| s.as_str()),
+            if_none_match: req
+                .header(header::IF_NONE_MATCH.as_str())
+                .map(|s| s.as_str()),
+            if_modified_since: req
+                .header(header::IF_MODIFIED_SINCE.as_str())
+                .map(|s| s.as_str()),
+            referrer: req.header(header::REFERER.as_str()).map(|s| s.as_str()),
+            minify: req.header(minify::BYPASS_HEADER).is_none(),
         }
     }
 
     /// Stream path (if any)...
     pub fn stream(self) -> BoxFuture<'a, Response> {
         async move {
-            let state = self.state;
-            match self.stream_().await {
+            let (state, actual_path, referrer) = (self.state, self.actual_path, self.referrer);
+            let request_id = self.request_id.clone();
+            let resp = match self.stream_().await {
                 Ok(r) => r,
                 Err(e) => {
-                    error!("{:?}", e);
-                    let mut resp = Response::builder(500)
-                        .body(state.body_5xx.clone())
-                        .header(header::CONTENT_DISPOSITION.as_str(), "inline")
-                        .header(
-                            header::CONTENT_LENGTH.as_str(),
-                            state.body_5xx.len().to_string(),
-                        )
-                        .build();
-                    resp.set_content_type(http_types::mime::HTML);
-                    resp
+                    let status = status_for_error(&e);
+                    if status.is_server_error() {
+                        error!("[{}] Error serving {}: {:?}", request_id, actual_path, e);
+                    } else {
+                        warn!(
+                            "[{}] Responding {} for {}: {:?}",
+                            request_id, status, actual_path, e
+                        );
+                    }
+
+                    state.error_response(status, &request_id)
+                }
+            };
+
+            if let Some(missing) = state.missing.as_ref() {
+                if resp.status() == StatusCode::NotFound {
+                    missing.record(actual_path, referrer);
                 }
             }
+
+            resp
         }
         .boxed()
     }
 
     async fn stream_(self) -> Result<Response, io::Error> {
-        let meta = fs::metadata(&self.path).await.ok();
-        // Check if the path exists and handle if it's a directory containing `index.html`
-        if meta.is_some() && meta.as_ref().map(|m| !m.is_file()).unwrap_or(false) {
-            // Redirect if path is a dir and URL doesn't end with "/"
-            if !self.actual_path.ends_with("/") {
-                let mut resp = self
-                    .resp
-                    .header(
-                        header::LOCATION.as_str(),
-                        String::from(self.actual_path) + "/",
-                    )
-                    .body(Body::empty()).build();
-                resp.set_status(StatusCode::MovedPermanently);
-                return Ok(resp);
-            }
-
-            let index = Path::new(self.actual_path).join("index.html");
-            let actual_path = &*index.to_string_lossy();
-            return Ok(Responder {
-                actual_path,
-                path: self.state.get_path(actual_path),
-                ..self
-            }
-            .stream()
-            .await);
-        }
-
-        match meta {
-            Some(m) => Ok(self.stream_using_meta(m).await?),
-            None => {
+        // Invalid paths are rejected (as bad requests) before touching the filesystem.
+        let path = self.path.clone()?;
+        match self.state.lookup(path, self.actual_path).await? {
+            Lookup::File(path, meta) => Ok(self.stream_using_meta(path, meta).await?),
+            Lookup::Redirect(location) => {
                 let mut resp = self
                     .resp
-                    .body(self.state.body_4xx.clone())
-                    .header(header::CONTENT_DISPOSITION.as_str(), "inline")
-                    .header(
-                        header::CONTENT_LENGTH.as_str(),
-                        self.state.body_4xx.len().to_string(),
-                    )
+                    .header(header::LOCATION.as_str(), location)
+                    .body(Body::empty())
                     .build();
-                resp.set_content_type(http_types::mime::HTML);
-                resp.set_status(StatusCode::NotFound);
+                resp.set_status(StatusCode::MovedPermanently);
                 Ok(resp)
             }
+            Lookup::NotFound => Ok(self
+                .state
+                .error_response(StatusCode::NotFound, &self.request_id)),
         }
     }
 
-    async fn stream_using_meta(mut self, meta: Metadata) -> Result<Response, io::Error> {
+    async fn stream_using_meta(
+        mut self,
+        path: PathBuf,
+        meta: Metadata,
+    ) -> Result<Response, io::Error> {
         let last_modified = meta.modified()?;
         let size = meta.len();
-        let etag = format!(
-            "{:x}-{:x}",
-            last_modified
-                .duration_since(UNIX_EPOCH)
-                .expect("unix epoch is wrong?")
-                .as_secs(),
-            size
-        );
+        let mut etag = etag(&meta)?;
+
+        let mime = mime_guess::from_path(&path).first_or_octet_stream();
+        let minify = match (&self.state.minifier, minify::Kind::from_mime(&mime)) {
+            (Some(m), Some(kind)) => {
+                // Minified files are a different representation (which the bypass header affects).
+                self.resp = self
+                    .resp
+                    .header(header::VARY.as_str(), minify::BYPASS_HEADER);
+                Some((m, kind)).filter(|_| self.minify)
+            }
+            _ => None,
+        };
+
+        if let Some(preloader) = self
+            .state
+            .preloader
+            .as_ref()
+            .filter(|_| mime == mime::TEXT_HTML)
+        {
+            if let Some(links) = preloader.links(self.actual_path, &path, &etag).await {
+                self.resp = self.resp.header(header::LINK.as_str(), links);
+            }
+        }
+
+        let source_etag = etag.clone();
+        if minify.is_some() {
+            etag.push_str("-min");
+        }
 
-        let mime = mime_guess::from_path(&self.path).first_or_octet_stream();
         self.resp = self
             .resp
             .header(
@@ -181,12 +348,7 @@ impl<'a> Responder<'a> {
                     _ => "attachment",
                 };
 
-                match self
-                    .path
-                    .file_name()
-                    .expect("already normalized path?")
-                    .to_str()
-                {
+                match path.file_name().expect("already normalized path?").to_str() {
                     Some(name) => format!(
                         "{}; filename*=\"{}\"",
                         ty,
@@ -194,8 +356,7 @@ impl<'a> Responder<'a> {
                             name,
                             percent_encoding::NON_ALPHANUMERIC
                         )
-                    )
-                    .into(),
+                    ),
                     None => String::from(ty),
                 }
             });
@@ -209,14 +370,45 @@ impl<'a> Responder<'a> {
                 .unwrap_or(false)
         };
 
+        if self.if_none_match.is_some() || self.if_modified_since.is_some() {
+            metrics::cache_lookup("http", respond_cache);
+        }
+
         if respond_cache {
             let mut resp = self.resp.body(Body::empty()).build();
             resp.set_status(StatusCode::NotModified);
             return Ok(resp);
         }
 
+        if let Some((minifier, kind)) = minify {
+            match minifier.minify(&path, &source_etag, kind).await {
+                Ok(bytes) => {
+                    let mut resp = self
+                        .resp
+                        .header(header::CONTENT_LENGTH.as_str(), bytes.len().to_string())
+                        .build();
+                    resp.set_status(StatusCode::Ok);
+                    resp.set_content_type(mime.as_ref());
+                    resp.set_body(Body::from_bytes(bytes.to_vec()));
+                    return Ok(resp);
+                }
+                Err(e) => {
+                    warn!(
+                        "[{}] Cannot minify {}: {}",
+                        self.request_id,
+                        path.display(),
+                        e
+                    );
+                    self.resp = self.resp.header(header::ETAG.as_str(), source_etag);
+                }
+            }
+        }
+
         // We're done with the checks. Stream file!
-        let mut resp = self.resp.header(header::CONTENT_LENGTH.as_str(), size.to_string()).build();
+        let mut resp = self
+            .resp
+            .header(header::CONTENT_LENGTH.as_str(), size.to_string())
+            .build();
         resp.set_status(StatusCode::Ok);
         resp.set_content_type(mime.as_ref());
 
@@ -225,7 +417,7 @@ impl<'a> Responder<'a> {
             return Ok(resp);
         }
 
-        let fd = BufReader::new(File::open(self.path).await?);
+        let fd = BufReader::new(File::open(path).await?);
         resp.set_body(Body::from_reader(fd, Some(size as usize)));
         Ok(resp)
     }
@@ -0,0 +1,267 @@
+use crate::serve;
+use crate::settings::CertificateSettings;
+use async_std::future;
+use async_std::net::TcpListener;
+use async_std::task;
+use futures::StreamExt;
+use futures_rustls::TlsAcceptor;
+use notify::{RecursiveMode, Watcher};
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{InconsistentKeys, ServerConfig};
+use tide::Server;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const WATCHER_DEBOUNCE_DURATION: Duration = Duration::from_secs(2);
+
+/// Errors in loading a certificate.
+#[derive(Debug)]
+pub enum CertificateError {
+    Io(io::Error),
+    NoCertificates,
+    NoKey,
+    Key(rustls::Error),
+    /// Key which isn't the one for the certificate.
+    Mismatch(rustls::Error),
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertificateError::Io(e) => write!(f, "I/O error: {}", e),
+            CertificateError::NoCertificates => f.write_str("no certificates found"),
+            CertificateError::NoKey => f.write_str("no private key found"),
+            CertificateError::Key(e) => write!(f, "unsupported private key: {}", e),
+            CertificateError::Mismatch(e) => {
+                write!(f, "private key doesn't match the certificate: {}", e)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for CertificateError {
+    fn from(e: io::Error) -> Self {
+        CertificateError::Io(e)
+    }
+}
+
+/// Load the certificate chain and its key from the PEM files.
+pub fn load_certificate(cert: &Path, key: &Path) -> Result<CertifiedKey, CertificateError> {
+    let mut reader = BufReader::new(File::open(cert)?);
+    let chain = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err(CertificateError::NoCertificates);
+    }
+
+    let mut reader = BufReader::new(File::open(key)?);
+    let key = rustls_pemfile::private_key(&mut reader)?.ok_or(CertificateError::NoKey)?;
+    let key = ring::sign::any_supported_type(&key).map_err(CertificateError::Key)?;
+    let certified = CertifiedKey::new(chain, key);
+    match certified.keys_match() {
+        // Keys which can't tell their public key are trusted.
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => Ok(certified),
+        Err(e) => Err(CertificateError::Mismatch(e)),
+    }
+}
+
+#[derive(Default)]
+struct Certificates {
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    /// Certificate for the clients without SNI (or for unknown hosts).
+    default: Option<Arc<CertifiedKey>>,
+}
+
+/// Certificate resolver which selects the certificate by SNI, and which can be
+/// reloaded while the listener is running.
+pub struct CertResolver {
+    settings: Vec<CertificateSettings>,
+    certificates: RwLock<Certificates>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl CertResolver {
+    /// Create a resolver for the given certificates (and load them).
+    pub fn new(settings: &[CertificateSettings]) -> Arc<Self> {
+        let resolver = Arc::new(CertResolver {
+            settings: settings.to_vec(),
+            certificates: RwLock::new(Certificates::default()),
+        });
+
+        resolver.reload();
+        resolver
+    }
+
+    /// Load all the certificates again. Certificates which fail to load are kept
+    /// as they were (if they've been loaded before).
+    pub fn reload(&self) {
+        let mut certificates = self
+            .certificates
+            .write()
+            .expect("certificates lock poisoned");
+        let mut reloaded = Certificates::default();
+        for (i, c) in self.settings.iter().enumerate() {
+            let key = match load_certificate(&c.cert, &c.key) {
+                Ok(k) => {
+                    info!("Loaded certificate {}", c.cert.display());
+                    Arc::new(k)
+                }
+                Err(e) => {
+                    error!("Cannot load certificate {}: {}", c.cert.display(), e);
+                    let old = match c.hosts.first() {
+                        Some(h) => certificates.hosts.get(&h.to_lowercase()),
+                        None => certificates.default.as_ref(),
+                    };
+
+                    match old {
+                        Some(k) => k.clone(),
+                        None => continue,
+                    }
+                }
+            };
+
+            if i == 0 {
+                reloaded.default = Some(key.clone());
+            }
+
+            // Earlier certificates take precedence for the same hosts.
+            for host in &c.hosts {
+                reloaded
+                    .hosts
+                    .entry(host.to_lowercase())
+                    .or_insert_with(|| key.clone());
+            }
+        }
+
+        *certificates = reloaded;
+    }
+
+    /// Reload the certificates whenever their files change.
+    pub fn watch(self: &Arc<Self>) {
+        let dirs = self
+            .settings
+            .iter()
+            .flat_map(|c| [&c.cert, &c.key])
+            .filter_map(|p| p.parent())
+            .map(|p| {
+                if p.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    p
+                }
+            })
+            .collect::<HashSet<_>>();
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher =
+            notify::watcher(tx, WATCHER_DEBOUNCE_DURATION).expect("cannot create watcher");
+        for dir in dirs {
+            // Certificates are usually symlinks which are replaced on renewal,
+            // so the directories are watched instead of the files.
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                error!("Cannot watch {}: {}", dir.display(), e);
+            }
+        }
+
+        let resolver = self.clone();
+        thread::spawn(move || {
+            let _watcher = watcher; // keep the watcher alive with the thread.
+            while rx.recv().is_ok() {
+                // Drain the burst of events from a renewal before reloading.
+                while rx.recv_timeout(WATCHER_DEBOUNCE_DURATION).is_ok() {}
+                info!("Reloading certificates.");
+                resolver.reload();
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificates = self
+            .certificates
+            .read()
+            .expect("certificates lock poisoned");
+        let name = match hello.server_name() {
+            Some(n) => n.to_lowercase(),
+            None => return certificates.default.clone(),
+        };
+
+        let wildcard = name.split_once('.').map(|(_, rest)| format!("*.{}", rest));
+        certificates
+            .hosts
+            .get(&name)
+            .or_else(|| wildcard.and_then(|w| certificates.hosts.get(&w)))
+            .or(certificates.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Serve the app over HTTPS on the given address.
+pub async fn listen<State: Clone + Send + Sync + 'static>(
+    app: Server<State>,
+    address: &str,
+    resolver: Arc<CertResolver>,
+) -> io::Result<()> {
+    // Only TLS 1.2 and 1.3 with forward secrecy and AEAD ciphers are supported.
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind(address).await?;
+    info!("Server listening on https://{}", listener.local_addr()?);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Cannot accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let (local_addr, peer_addr) = match (stream.local_addr(), stream.peer_addr()) {
+            (Ok(l), Ok(p)) => (l, p),
+            _ => continue,
+        };
+
+        // Responses are written in a few pieces, which shouldn't wait for each other.
+        if let Err(e) = stream.set_nodelay(true) {
+            debug!("Cannot disable Nagle's algorithm for {}: {}", peer_addr, e);
+        }
+
+        let (app, acceptor) = (app.clone(), acceptor.clone());
+        task::spawn(async move {
+            let stream = match future::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => return debug!("TLS handshake with {} failed: {}", peer_addr, e),
+                Err(_) => return debug!("TLS handshake with {} timed out", peer_addr),
+            };
+
+            serve::serve_connection(app, stream, "https", local_addr, peer_addr).await;
+        });
+    }
+
+    Ok(())
+}
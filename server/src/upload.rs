@@ -0,0 +1,342 @@
+use crate::resolver;
+use crate::server::{PRIVATE_PATH_PREFIX, UPLOAD_PATH_PREFIX};
+use crate::staticfile;
+use crate::watcher::{self, PrivateLinks};
+use crate::{auth, util};
+use async_std::fs::{self, File};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::task;
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use http_types::headers::{CONTENT_TYPE, LOCATION};
+use tide::{Body, Endpoint, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Directory (inside private root) where uploads are staged before they're renamed.
+const STAGING_DIR: &str = ".uploads";
+const CHUNK_SIZE: usize = 64 * 1024;
+const REFLECT_TIMEOUT: Duration = Duration::from_secs(30);
+const REFLECT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+enum UploadError {
+    TooLarge(u64),
+    BadPath,
+    Multipart(multer::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::TooLarge(limit) => write!(f, "upload exceeds {} bytes", limit),
+            UploadError::BadPath => f.write_str("invalid path"),
+            UploadError::Multipart(e) => write!(f, "multipart error: {}", e),
+            UploadError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+impl From<multer::Error> for UploadError {
+    fn from(e: multer::Error) -> Self {
+        match e {
+            multer::Error::StreamSizeExceeded { limit }
+            | multer::Error::FieldSizeExceeded { limit, .. } => UploadError::TooLarge(limit),
+            e => UploadError::Multipart(e),
+        }
+    }
+}
+
+impl UploadError {
+    fn status(&self) -> StatusCode {
+        match self {
+            UploadError::TooLarge(_) => StatusCode::PayloadTooLarge,
+            UploadError::BadPath | UploadError::Multipart(_) => StatusCode::BadRequest,
+            UploadError::Io(e) => staticfile::status_for_error(e),
+        }
+    }
+}
+
+/// A file which has been moved into private root.
+#[derive(Serialize)]
+struct Uploaded {
+    path: String,
+    size: u64,
+    /// Private link (if the watcher has reflected the file in time).
+    link: Option<String>,
+}
+
+/// Endpoint for streaming (authenticated) uploads into the private root.
+///
+/// - `PUT /_upload/<path>` stores the request body at `<path>`.
+/// - `POST /_upload/<dir>` stores all the files in a `multipart/form-data` body inside `<dir>`.
+pub struct Upload {
+    root: PathBuf,
+    reflect_path: PathBuf,
+    links: PrivateLinks,
+    /// Maximum size of an upload (a single file, or all the parts of a multipart
+    /// body together) in bytes.
+    size_limit: u64,
+}
+
+impl Upload {
+    /// Create an endpoint which uploads to the given private root.
+    pub fn new(
+        root: impl AsRef<Path>,
+        reflect_path: impl AsRef<Path>,
+        links: PrivateLinks,
+        size_limit: u64,
+    ) -> Self {
+        Upload {
+            root: PathBuf::from(root.as_ref()),
+            reflect_path: PathBuf::from(reflect_path.as_ref()),
+            links,
+            size_limit,
+        }
+    }
+
+    /// Relative path (inside private root) from the request path.
+    fn relative_path(path: &str) -> Result<PathBuf, UploadError> {
+        let path = path.strip_prefix(UPLOAD_PATH_PREFIX).unwrap_or(path);
+        let rel_path = resolver::resolve(Path::new(""), path).map_err(|_| UploadError::BadPath)?;
+        match rel_path.components().next() {
+            Some(Component::Normal(head)) if watcher::is_hidden(&head.to_string_lossy()) => {
+                Err(UploadError::BadPath)
+            }
+            _ => Ok(rel_path),
+        }
+    }
+
+    /// Stream the chunks into a staging file and move it to the given path once it's done.
+    async fn save<S, E>(&self, mut chunks: S, rel_path: &Path) -> Result<u64, UploadError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        UploadError: From<E>,
+    {
+        if rel_path.as_os_str().is_empty() {
+            return Err(UploadError::BadPath);
+        }
+
+        let staging = self.root.join(STAGING_DIR);
+        fs::create_dir_all(&staging).await?;
+        let tmp_path = staging.join(Uuid::new_v4().simple().to_string());
+
+        let result = async {
+            let mut fd = File::create(&tmp_path).await?;
+            let mut size = 0;
+            while let Some(chunk) = chunks.try_next().await? {
+                size += chunk.len() as u64;
+                if size > self.size_limit {
+                    return Err(UploadError::TooLarge(self.size_limit));
+                }
+
+                fd.write_all(&chunk).await?;
+            }
+
+            fd.sync_all().await?;
+            let path = self.root.join(rel_path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+
+            fs::rename(&tmp_path, &path).await?;
+            info!("Uploaded {} ({} bytes)", path.display(), size);
+            Ok(size)
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path).await;
+        }
+
+        result
+    }
+
+    /// Wait for the watcher to reflect the given path and return its private link.
+    async fn wait_for_link(&self, rel_path: &Path, size: u64) -> Option<String> {
+        let head = match rel_path.components().next() {
+            Some(Component::Normal(head)) => head.to_string_lossy().into_owned(),
+            _ => return None,
+        };
+
+        let start = Instant::now();
+        while start.elapsed() < REFLECT_TIMEOUT {
+            let id = self
+                .links
+                .read()
+                .expect("links lock poisoned")
+                .get(&head)
+                .copied();
+            if let Some(id) = id {
+                let token = id.hyphenated().to_string();
+                let reflected = self.reflect_path.join(&token).join(rel_path);
+                if fs::metadata(&reflected)
+                    .await
+                    .map(|m| m.len() == size)
+                    .unwrap_or(false)
+                {
+                    return Some(format!(
+                        "{}/{}/{}",
+                        PRIVATE_PATH_PREFIX,
+                        token,
+                        util::encode_path(rel_path)
+                    ));
+                }
+            }
+
+            task::sleep(REFLECT_POLL_INTERVAL).await;
+        }
+
+        warn!(
+            "Timed out waiting for {} to be reflected.",
+            rel_path.display()
+        );
+        None
+    }
+
+    /// Save all the files in the multipart body inside the given directory.
+    async fn save_parts(
+        &self,
+        mut multipart: multer::Multipart<'_>,
+        rel_path: &Path,
+        files: &mut Vec<(PathBuf, u64)>,
+    ) -> Result<(), UploadError> {
+        while let Some(field) = multipart.next_field().await? {
+            let name = match field.file_name() {
+                Some(n) => n.to_owned(),
+                None => continue,
+            };
+
+            if !is_valid_file_name(&name) {
+                return Err(UploadError::BadPath);
+            }
+
+            let path = rel_path.join(&name);
+            let size = self.save(field, &path).await?;
+            files.push((path, size));
+        }
+
+        Ok(())
+    }
+
+    async fn upload<State>(&self, mut req: Request<State>) -> Result<Vec<Uploaded>, UploadError> {
+        if req
+            .len()
+            .map(|l| l as u64 > self.size_limit)
+            .unwrap_or(false)
+        {
+            return Err(UploadError::TooLarge(self.size_limit));
+        }
+
+        let rel_path = Self::relative_path(req.url().path())?;
+        let boundary = req
+            .header(CONTENT_TYPE)
+            .filter(|v| v.as_str().starts_with("multipart/form-data"))
+            .map(|v| multer::parse_boundary(v.as_str()))
+            .transpose()?;
+        let body = body_stream(req.take_body());
+
+        let mut files = vec![];
+        match boundary {
+            Some(boundary) => {
+                // Parts are limited together, so that many of them can't add up to more.
+                let constraints = multer::Constraints::new()
+                    .size_limit(multer::SizeLimit::new().whole_stream(self.size_limit));
+                let multipart = multer::Multipart::with_constraints(body, boundary, constraints);
+                if let Err(e) = self.save_parts(multipart, &rel_path, &mut files).await {
+                    // Requests are rejected as a whole, including the parts saved so far.
+                    for (path, _) in &files {
+                        let _ = fs::remove_file(self.root.join(path)).await;
+                    }
+
+                    return Err(e);
+                }
+            }
+            None => {
+                let size = self.save(body, &rel_path).await?;
+                files.push((rel_path, size));
+            }
+        }
+
+        let mut uploaded = vec![];
+        for (path, size) in files {
+            uploaded.push(Uploaded {
+                link: self.wait_for_link(&path, size).await,
+                path: path.display().to_string(),
+                size,
+            });
+        }
+
+        Ok(uploaded)
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for Upload {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !auth::is_authorized(&req) {
+            return Ok(auth::unauthorized());
+        }
+
+        let uploaded = match self.upload(req).await {
+            Ok(u) => u,
+            Err(e) => {
+                warn!("Rejecting upload: {}", e);
+                return Ok(Response::new(e.status()));
+            }
+        };
+
+        let status = if uploaded.iter().all(|u| u.link.is_some()) {
+            StatusCode::Created
+        } else {
+            StatusCode::Accepted
+        };
+
+        let mut resp = Response::new(status);
+        if let [Uploaded {
+            link: Some(link), ..
+        }] = &*uploaded
+        {
+            resp.insert_header(LOCATION, link.as_str());
+        }
+
+        resp.set_body(Body::from_json(&uploaded)?);
+        Ok(resp)
+    }
+}
+
+/// Whether the file name (from a multipart field) is a single, visible component.
+fn is_valid_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains(['\\', '\0'])
+        && !watcher::is_hidden(name)
+}
+
+/// Stream the request body in chunks.
+fn body_stream(body: Body) -> impl Stream<Item = io::Result<Bytes>> + Unpin {
+    stream::try_unfold(body, |mut body| async move {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let n = body.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        buf.truncate(n);
+        Ok(Some((Bytes::from(buf), body)))
+    })
+    .boxed()
+}
@@ -1,30 +1,46 @@
 use chrono::{offset::Utc, SecondsFormat};
 use env_logger::Builder;
+use futures::io::{AsyncBufRead, AsyncRead};
 use log::LevelFilter;
+use percent_encoding::{AsciiSet, CONTROLS};
+use tide::{Body, Response};
 
-use std::env;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Component, Path};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-/// Prepares the logger with the universal datetime format and INFO level.
-pub fn prepare_logger() {
+/// Characters which need to be encoded in a path segment.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Prepares the logger with the universal datetime format and the given filters.
+pub fn prepare_logger(filters: &str) {
     let mut builder = Builder::new();
     builder
         .format(|buf, record| {
-            write!(
+            writeln!(
                 buf,
-                "{}: {}: {}\n",
+                "{}: {}: {}",
                 Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
                 record.level(),
                 record.args()
             )
         })
         .filter_level(LevelFilter::Info);
-    if env::var("LOG_LEVEL").is_ok() {
-        builder = Builder::from_env("LOG_LEVEL");
-    }
-
+    builder.parse_filters(filters);
     builder.init();
 }
 
@@ -59,3 +75,117 @@ where
     let mut bytes = vec![];
     File::open(path.as_ref()).and_then(|mut fd| fd.read_to_end(&mut bytes).map(|_| bytes))
 }
+
+/// Recursively copy the given directory, skipping the files which already exist
+/// (with the same size) in the destination.
+pub fn copy_dir_missing<P, Q>(source: P, dest: Q) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    create_dir_if_not_exists(dest.as_ref());
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let new_path = dest.as_ref().join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_missing(entry.path(), &new_path)?;
+        } else if fs::metadata(&new_path).map(|m| m.len()).ok() != Some(entry.metadata()?.len()) {
+            info!(
+                "Copying {} to {}",
+                entry.path().display(),
+                new_path.display()
+            );
+            fs::copy(entry.path(), &new_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Percent-encode the components of a relative path for use in URLs.
+pub fn encode_path<P>(path: P) -> String
+where
+    P: AsRef<Path>,
+{
+    path.as_ref()
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(
+                percent_encoding::utf8_percent_encode(&s.to_string_lossy(), PATH_SEGMENT)
+                    .to_string(),
+            ),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Escape the string for use in XML (or HTML) text and attributes.
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Response body which calls the function with the number of bytes sent, once
+/// it's been streamed (or dropped).
+struct ObservedBody<F: FnOnce(u64)> {
+    body: Body,
+    sent: u64,
+    on_end: Option<F>,
+}
+
+impl<F: FnOnce(u64) + Unpin> AsyncRead for ObservedBody<F> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.body).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.sent += n as u64;
+        }
+
+        result
+    }
+}
+
+impl<F: FnOnce(u64) + Unpin> AsyncBufRead for ObservedBody<F> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.sent += amt as u64;
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+impl<F: FnOnce(u64)> Drop for ObservedBody<F> {
+    fn drop(&mut self) {
+        if let Some(f) = self.on_end.take() {
+            f(self.sent);
+        }
+    }
+}
+
+/// Call the function with the number of bytes sent once the body of the response
+/// has been streamed (or dropped).
+pub fn observe_body<F>(resp: &mut Response, on_end: F)
+where
+    F: FnOnce(u64) + Send + Sync + Unpin + 'static,
+{
+    let body = resp.take_body();
+    let (len, mime) = (body.len(), body.mime().clone());
+    let mut body = Body::from_reader(
+        ObservedBody {
+            body,
+            sent: 0,
+            on_end: Some(on_end),
+        },
+        len,
+    );
+    body.set_mime(mime);
+    resp.set_body(body);
+}
@@ -1,24 +1,27 @@
+use crate::acl::AccessRule;
+use crate::health::Heartbeat;
+use crate::metrics;
+use crate::settings::WatcherSettings;
 use crate::util;
 use chrono::offset::Utc;
 use chrono::{DateTime, Duration as TimeDelta};
 use crossbeam_channel::{self as mpmc, Receiver as MpmcReceiver, Sender as MpmcSender};
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{self, File};
+use std::net::IpAddr;
 use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 // TODO: This is old and huge. Need to refactor and add tests!
 
-const WATCHER_SLEEP_DURATION: Duration = Duration::from_millis(1000);
-const WATCHER_SMS_DURATION: Duration = Duration::from_secs(5 * 60);
-const SMS_LIMIT: usize = 140; // 160 GSM chars or 140 ASCII chars
-
 /// Indicates the lifetime of this token.
 #[derive(Clone, Copy, Deserialize, Serialize)]
 struct TokenRotation {
@@ -47,15 +50,15 @@ impl TokenRotation {
     fn expiry(&self) -> DateTime<Utc> {
         let mut delta = TimeDelta::zero();
         if let Some(m) = self.minutes {
-            delta = delta + TimeDelta::minutes(m as i64);
+            delta += TimeDelta::minutes(m as i64);
         }
 
         if let Some(h) = self.hours {
-            delta = delta + TimeDelta::hours(h as i64);
+            delta += TimeDelta::hours(h as i64);
         }
 
         if let Some(d) = self.days {
-            delta = delta + TimeDelta::days(d as i64);
+            delta += TimeDelta::days(d as i64);
         }
 
         Utc::now() + delta
@@ -63,13 +66,16 @@ impl TokenRotation {
 }
 
 /// Represents a private link. By default, expiry is one day.
-#[derive(Clone, Copy, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 struct PrivateLink {
     id: Uuid,
     expiry: Option<DateTime<Utc>>,
     rotation: TokenRotation,
     #[serde(default)]
     skip_sms: bool,
+    /// Rules for the client IPs (the first one with a matching range applies).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    access: Vec<AccessRule>,
 }
 
 impl Default for PrivateLink {
@@ -80,6 +86,7 @@ impl Default for PrivateLink {
             expiry: Some(rotation.expiry()),
             rotation,
             skip_sms: false,
+            access: vec![],
         }
     }
 }
@@ -108,6 +115,27 @@ impl PrivateLink {
     }
 }
 
+/// Shared view of the current tokens for the entries in private root.
+pub type PrivateLinks = Arc<RwLock<HashMap<String, Uuid>>>;
+
+/// Access of a private entry (by its token and name) from a client (if it's known).
+pub type PrivateHit = (Uuid, String, Option<IpAddr>);
+
+/// Counts of the accesses of the private entries, with the clients they came from.
+type Accesses = HashMap<(Uuid, String), (usize, BTreeSet<IpAddr>)>;
+
+/// Number of client IPs listed for each entry in the notifications.
+const MAX_NOTIFIED_IPS: usize = 3;
+
+/// Shared view of the access rules for the current tokens (of the entries which have any).
+pub type PrivateAccess = Arc<RwLock<HashMap<Uuid, Vec<AccessRule>>>>;
+
+/// Whether the given entry in private root should be skipped (hidden entries
+/// are used for staging uploads, and they're never shared).
+pub fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
 /// Wrapper around notifier to watch a directory for private resources and generate
 /// UUID-based links in the actual source directory.
 pub struct PrivateWatcher {
@@ -115,28 +143,71 @@ pub struct PrivateWatcher {
     reflect_path: PathBuf,
     config_path: PathBuf,
     config: HashMap<String, PrivateLink>,
+    /// Whether the config file has errors (in which case it's left for the operator to fix).
+    config_invalid: bool,
+    links: PrivateLinks,
+    access: PrivateAccess,
     event_receiver: Receiver<DebouncedEvent>,
-    access_receiver: MpmcReceiver<(Uuid, String)>,
+    access_receiver: MpmcReceiver<PrivateHit>,
     watcher: RecommendedWatcher,
+    /// Interval for checking the events and the config.
+    sleep_duration: Duration,
+    /// Interval for sending the accesses of private paths.
+    sms_duration: Duration,
+    /// Flag for stopping the watcher after its current iteration.
+    stop: Arc<AtomicBool>,
+    heartbeat: Heartbeat,
 }
 
 impl PrivateWatcher {
     /// Initialize this watcher with a root and reflect path.
-    pub fn new(config_path: &str, root_path: &str, reflect_path: &str) -> Self {
+    pub fn new(
+        config_path: impl AsRef<Path>,
+        root_path: impl AsRef<Path>,
+        reflect_path: impl AsRef<Path>,
+        settings: &WatcherSettings,
+    ) -> Self {
         let (tx, rx) = mpsc::channel();
         PrivateWatcher {
-            root_path: PathBuf::from(root_path),
-            reflect_path: PathBuf::from(reflect_path),
-            config_path: PathBuf::from(config_path),
+            root_path: PathBuf::from(root_path.as_ref()),
+            reflect_path: PathBuf::from(reflect_path.as_ref()),
+            config_path: PathBuf::from(config_path.as_ref()),
             config: HashMap::new(),
+            config_invalid: false,
+            links: PrivateLinks::default(),
+            access: PrivateAccess::default(),
             event_receiver: rx,
             access_receiver: mpmc::unbounded().1, // set default for now
             watcher: Watcher::new(tx, Duration::from_secs(2)).expect("cannot create watcher"),
+            sleep_duration: settings.sleep_duration(),
+            sms_duration: settings.sms_duration(),
+            stop: Arc::new(AtomicBool::new(false)),
+            heartbeat: Heartbeat::default(),
         }
     }
 
+    /// Get a handle to the links which are being served currently.
+    pub fn links(&self) -> PrivateLinks {
+        self.links.clone()
+    }
+
+    /// Get a handle to the access rules of the links which are being served currently.
+    pub fn access(&self) -> PrivateAccess {
+        self.access.clone()
+    }
+
+    /// Get a flag which stops the watcher (after its current iteration) once it's set.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Get the heartbeat of the watcher, which beats on every iteration.
+    pub fn heartbeat(&self) -> Heartbeat {
+        self.heartbeat.clone()
+    }
+
     /// Cleanup, create replicas in the serving directory, and start watching.
-    pub fn initialize(&mut self) -> MpmcSender<(Uuid, String)> {
+    pub fn initialize(&mut self) -> MpmcSender<PrivateHit> {
         info!("Cleaning up private directory.");
         if self.reflect_path.exists() {
             util::remove_any_path(&self.reflect_path);
@@ -150,10 +221,11 @@ impl PrivateWatcher {
             .filter_map(|e| e.ok())
         {
             let name = String::from(entry.file_name().to_str().unwrap());
-            let link = self
-                .config
-                .entry(name.clone())
-                .or_insert(PrivateLink::default());
+            if is_hidden(&name) {
+                continue;
+            }
+
+            let link = self.config.entry(name.clone()).or_default();
             let id = link.get_token();
             let dir_path = self.reflect_path.join(&id);
             util::create_dir_if_not_exists(&dir_path);
@@ -165,14 +237,16 @@ impl PrivateWatcher {
                 new_path.display()
             );
             info!("Expiry time set to: {}", link.expiry.unwrap());
+            let start = Instant::now();
             Command::new("cp")
-                .args(&[
+                .args([
                     "-r",
                     &entry.path().display().to_string(),
                     &new_path.display().to_string(),
                 ])
                 .output()
                 .expect("recursive copy");
+            metrics::observe_watcher("copy", start.elapsed());
         }
 
         self.check_config();
@@ -191,7 +265,7 @@ impl PrivateWatcher {
     where
         F: FnMut(Uuid, String) -> Option<String>,
     {
-        for entry in fs::read_dir(&source).unwrap().filter_map(|e| e.ok()) {
+        for entry in fs::read_dir(source).unwrap().filter_map(|e| e.ok()) {
             let path = entry.path();
             if !path.is_dir() {
                 info!("Ignoring {} because it's not a directory.", path.display());
@@ -215,7 +289,7 @@ impl PrivateWatcher {
             let mut entries = fs::read_dir(&dir_path)
                 .expect("dir walking")
                 .filter_map(|e| e.ok());
-            let first_entry = match (&mut entries).next() {
+            let first_entry = match entries.next() {
                 Some(e) => e,
                 None => {
                     info!("Removing empty directory: {}", dir_path.display());
@@ -224,7 +298,7 @@ impl PrivateWatcher {
                 }
             };
 
-            if (&mut entries).next().is_some() {
+            if entries.next().is_some() {
                 error!("{} has more than one entry!", dir_path.display());
                 continue;
             }
@@ -245,13 +319,35 @@ impl PrivateWatcher {
         }
     }
 
-    // Load the config from default path - if there's none, or if it has errors,
-    // then default to empty.
+    // Load the config from default path - if there's none, then default to empty.
+    // If it has errors, then keep the current one (so that the access rules of the
+    // entries aren't dropped by a typo) until it's fixed.
     fn load_config(&mut self) {
-        self.config = File::open(&self.config_path)
-            .ok()
-            .and_then(|mut fd| serde_json::from_reader(&mut fd).ok())
-            .unwrap_or_else(|| HashMap::new());
+        let fd = match File::open(&self.config_path) {
+            Ok(fd) => fd,
+            Err(_) => {
+                self.config_invalid = false;
+                return self.config = HashMap::new();
+            }
+        };
+
+        match serde_json::from_reader(fd) {
+            Ok(config) => {
+                self.config = config;
+                self.config_invalid = false;
+            }
+            Err(e) => {
+                if !self.config_invalid {
+                    error!(
+                        "Keeping the last valid config, as {} is invalid: {}",
+                        self.config_path.display(),
+                        e
+                    );
+                }
+
+                self.config_invalid = true;
+            }
+        }
     }
 
     /// Load/reload config and ensure cleanliness in serve directory and config.
@@ -263,11 +359,13 @@ impl PrivateWatcher {
             &self.root_path.clone(),
             &self.reflect_path.clone(),
             |uuid, name| {
-                if self.config.get(&name).is_none() {
-                    // This happens when the config is not a valid JSON, and we've defaulted to empty.
+                if !self.config.contains_key(&name) {
+                    // This happens for new entries, or when there's no (valid) config to begin with.
                     // At this point, we have no choice but to land on the default rotation for that link.
-                    let mut link = PrivateLink::default();
-                    link.id = uuid;
+                    let link = PrivateLink {
+                        id: uuid,
+                        ..PrivateLink::default()
+                    };
                     info!("Adding missing link for {}:{} to config.", name, link.id);
                     self.config.insert(name.clone(), link);
                 }
@@ -298,10 +396,37 @@ impl PrivateWatcher {
         self.config
             .retain(|ref parent, link| source.join(link.get_token()).join(parent).exists());
 
-        // dump/overwrite the config
-        File::create(&self.config_path)
-            .ok()
-            .and_then(|mut fd| serde_json::to_writer_pretty(&mut fd, &self.config).ok());
+        // dump/overwrite the config (unless it has errors, which would be lost)
+        if !self.config_invalid {
+            File::create(&self.config_path)
+                .ok()
+                .and_then(|mut fd| serde_json::to_writer_pretty(&mut fd, &self.config).ok());
+        }
+
+        let next_expiry = self
+            .config
+            .values()
+            .filter_map(|l| l.expiry)
+            .min()
+            .map(|dt| (dt - Utc::now()).to_std().unwrap_or_default());
+        metrics::set_private_links(self.config.len(), next_expiry);
+
+        let mut links = self.links.write().expect("links lock poisoned");
+        links.clear();
+        links.extend(
+            self.config
+                .iter()
+                .map(|(name, link)| (name.clone(), link.id)),
+        );
+
+        let mut access = self.access.write().expect("access lock poisoned");
+        access.clear();
+        access.extend(
+            self.config
+                .values()
+                .filter(|link| !link.access.is_empty())
+                .map(|link| (link.id, link.access.clone())),
+        );
     }
 
     /// Find the head component (file or dir) of the given path.
@@ -321,14 +446,20 @@ impl PrivateWatcher {
     /// Reflect source from the given `Path` (which should a sub-path of `SERVE_PATH_ROOT`).
     fn reflect_source(&mut self, path: &Path) {
         let rel_path = path.strip_prefix(&self.root_path).unwrap();
-        let parent = self.find_head(&rel_path);
-        let link = self.config.entry(parent).or_insert(PrivateLink::default());
+        let parent = self.find_head(rel_path);
+        if is_hidden(&parent) {
+            return;
+        }
+
+        let start = Instant::now();
+        let link = self.config.entry(parent).or_default();
         let id = link.get_token();
 
         let new_path = self.reflect_path.join(&id).join(rel_path);
         if path.exists() {
             if path.is_dir() {
-                util::create_dir_if_not_exists(&new_path);
+                // Directories could've been populated before we started watching them.
+                util::copy_dir_missing(path, &new_path).expect("copying directory");
             } else {
                 info!(
                     "Copying detected path {} to {}",
@@ -336,26 +467,34 @@ impl PrivateWatcher {
                     &new_path.display()
                 );
                 let parent = new_path.parent().unwrap();
-                util::create_dir_if_not_exists(&parent);
+                util::create_dir_if_not_exists(parent);
                 fs::copy(path, &new_path).expect("copying file");
             }
+
+            metrics::observe_watcher("copy", start.elapsed());
         } else {
             if new_path.exists() {
                 info!("Removing {}", new_path.display());
                 util::remove_any_path(&new_path);
             }
         }
+
+        metrics::observe_watcher("reflect", start.elapsed());
     }
 
     /// Start watching for events and handle them accordingly.
     pub fn start_watching(mut self) {
         let mut notify_time = Instant::now();
         let mut accesses = HashMap::new();
+        // The watcher is gone (as far as readiness goes) once this is dropped.
+        let heartbeat = self.heartbeat.clone();
+        let _alive = heartbeat.guard();
 
         // FIXME: Once `notify` has futures-mpsc support, let's switch to
         // `tokio_core::reactor::Interval` for periodic notifications
         // and select over both the streams (instead of try_recv).
-        loop {
+        while !self.stop.load(Ordering::SeqCst) {
+            heartbeat.beat();
             // We're loading the config before handling the events, because
             // `reflect_source` will mutate the config.
             self.load_config();
@@ -372,40 +511,69 @@ impl PrivateWatcher {
             }
 
             self.check_config();
-            thread::sleep(WATCHER_SLEEP_DURATION);
+            thread::sleep(self.sleep_duration);
 
             // SMS private path accesses over some interval.
-            while let Ok((uuid, sub_path)) = self.access_receiver.try_recv() {
-                match self.config.get(&sub_path) {
-                    Some(l) if l.id == uuid && !l.skip_sms => (),
-                    _ => continue,
-                }
-
-                let c = accesses.entry((uuid, sub_path)).or_insert(0);
-                *c += 1;
+            self.count_accesses(&mut accesses);
+            if notify_time.elapsed() > self.sms_duration {
+                notify_time = Instant::now();
+                Self::notify_accesses(&mut accesses);
             }
+        }
 
-            if notify_time.elapsed() > WATCHER_SMS_DURATION {
-                notify_time = Instant::now();
-                if accesses.is_empty() {
-                    continue;
-                }
+        // Accesses from the last interval shouldn't be lost on shutdown.
+        info!("Stopping watcher.");
+        self.count_accesses(&mut accesses);
+        Self::notify_accesses(&mut accesses);
+    }
 
-                let mut vec = accesses.drain().collect::<Vec<_>>();
-                vec.sort_by(|(_, a), (_, b)| b.cmp(&a)); // sort descending by counts
-                let mut msg = format!("Caution!");
-                for ((_id, p), c) in vec {
-                    let entry = format!("\n{}: {}", p, c);
-                    if msg.len() + entry.len() > (SMS_LIMIT - 4) {
-                        msg.push_str("\n..."); // trim after single message length
-                        break;
-                    } else {
-                        msg.push_str(&entry);
+    /// Collect the accesses of private paths which have been received so far.
+    fn count_accesses(&self, accesses: &mut Accesses) {
+        while let Ok((uuid, sub_path, ip)) = self.access_receiver.try_recv() {
+            match self.config.get(&sub_path) {
+                Some(l) if l.id == uuid => {
+                    metrics::private_access(&sub_path);
+                    if l.skip_sms {
+                        continue;
                     }
                 }
-
-                async_std::task::block_on(crate::sms::send(&msg));
+                _ => continue,
             }
+
+            let (c, ips) = accesses.entry((uuid, sub_path)).or_default();
+            *c += 1;
+            ips.extend(ip);
+        }
+    }
+
+    /// Send the collected accesses (if any).
+    fn notify_accesses(accesses: &mut Accesses) {
+        if accesses.is_empty() {
+            return;
         }
+
+        let mut vec = accesses.drain().collect::<Vec<_>>();
+        vec.sort_by(|(_, (a, _)), (_, (b, _))| b.cmp(a)); // sort descending by counts
+        let msg = crate::sms::summarize(
+            "Caution!",
+            vec.into_iter().map(|((_id, p), (c, ips))| {
+                let mut line = format!("{}: {}", p, c);
+                if !ips.is_empty() {
+                    let mut clients = ips
+                        .iter()
+                        .take(MAX_NOTIFIED_IPS)
+                        .map(|ip| ip.to_string())
+                        .collect::<Vec<_>>();
+                    if ips.len() > MAX_NOTIFIED_IPS {
+                        clients.push(format!("+{}", ips.len() - MAX_NOTIFIED_IPS));
+                    }
+
+                    line.push_str(&format!(" ({})", clients.join(", ")));
+                }
+
+                line
+            }),
+        );
+        async_std::task::block_on(crate::sms::send(&msg));
     }
 }
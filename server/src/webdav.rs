@@ -0,0 +1,293 @@
+use crate::auth;
+use crate::proxy;
+use crate::server::DAV_PATH_PREFIX;
+use crate::staticfile::{self, Responder, StaticFile};
+use crate::util;
+use crate::watcher::{PrivateHit, PrivateLinks};
+use async_std::fs::{self, Metadata};
+use async_std::stream::StreamExt;
+use crossbeam_channel::Sender;
+use http_types::headers::{ALLOW, CONTENT_TYPE, LOCATION};
+use http_types::Method;
+use tide::{Body, Endpoint, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+/// Sub-prefix for the public tree.
+const PUBLIC_MOUNT: &str = "public";
+/// Sub-prefix for the private tree.
+const PRIVATE_MOUNT: &str = "private";
+/// Sub-prefix for per-token views of private shares.
+pub const SHARE_MOUNT: &str = "share";
+
+/// A resolved WebDAV request.
+struct Mount {
+    /// URL prefix of this mount (ending with `/`).
+    prefix: String,
+    /// Handler pointing to the root of this mount.
+    state: StaticFile,
+    /// Path relative to the root of this mount.
+    rel_path: String,
+    /// Token and entry (if this is a share).
+    share: Option<(Uuid, String)>,
+}
+
+/// Reasons for not finding a mount.
+enum MountError {
+    Unauthorized,
+    NotFound,
+    /// Mount roots should be requested with a trailing slash.
+    Redirect(String),
+}
+
+impl From<MountError> for Response {
+    fn from(e: MountError) -> Self {
+        match e {
+            MountError::Unauthorized => auth::unauthorized(),
+            MountError::NotFound => Response::new(StatusCode::NotFound),
+            MountError::Redirect(location) => Response::builder(StatusCode::MovedPermanently)
+                .header(LOCATION, location)
+                .build(),
+        }
+    }
+}
+
+/// Read-only WebDAV endpoint for browsing the served trees.
+///
+/// - `/_dav/public/` maps to the public root and `/_dav/private/` maps to the private root.
+///   Both of them need the admin token (as the basic auth password).
+/// - `/_dav/share/<token>/` maps to a single private share, for which the token is enough.
+#[derive(Clone)]
+pub struct WebDav {
+    public: StaticFile,
+    private: StaticFile,
+    reflect_path: PathBuf,
+    links: PrivateLinks,
+    sender: Sender<PrivateHit>,
+}
+
+impl WebDav {
+    /// Create an endpoint for the given public root, private root and the private serving path.
+    pub fn new(
+        public: &StaticFile,
+        private_root: impl AsRef<Path>,
+        reflect_path: impl AsRef<Path>,
+        links: PrivateLinks,
+        sender: Sender<PrivateHit>,
+    ) -> Self {
+        // Clients should get the files as they are.
+        let mut public = public.clone();
+        public.set_minifier(None);
+        public.set_preloader(None);
+        public.set_missing_paths(None);
+        WebDav {
+            private: public.with_root(private_root),
+            public,
+            reflect_path: PathBuf::from(reflect_path.as_ref()),
+            links,
+            sender,
+        }
+    }
+
+    /// Find the mount for the given request path.
+    fn mount<State>(&self, req: &Request<State>) -> Result<Mount, MountError> {
+        let path = req.url().path();
+        let path = path.strip_prefix(DAV_PATH_PREFIX).unwrap_or(path);
+        let mut parts = path.trim_start_matches('/').splitn(2, '/');
+        let mount = parts.next().unwrap_or("");
+        let rest = parts.next();
+
+        let (state, prefix, rest, share) = match mount {
+            PUBLIC_MOUNT | PRIVATE_MOUNT if !auth::is_authorized(req) => {
+                return Err(MountError::Unauthorized)
+            }
+            PUBLIC_MOUNT => (
+                self.public.clone(),
+                format!("{}/{}/", DAV_PATH_PREFIX, mount),
+                rest,
+                None,
+            ),
+            PRIVATE_MOUNT => (
+                self.private.clone(),
+                format!("{}/{}/", DAV_PATH_PREFIX, mount),
+                rest,
+                None,
+            ),
+            SHARE_MOUNT => {
+                let mut parts = rest.unwrap_or("").splitn(2, '/');
+                let token = parts.next().unwrap_or("");
+                let entry = self
+                    .links
+                    .read()
+                    .expect("links lock poisoned")
+                    .iter()
+                    .find(|(_, id)| token.parse::<Uuid>().ok() == Some(**id))
+                    .map(|(name, id)| (*id, name.clone()));
+                let (id, name) = entry.ok_or(MountError::NotFound)?;
+                let token = id.hyphenated().to_string();
+                (
+                    self.public.with_root(self.reflect_path.join(&token)),
+                    format!("{}/{}/{}/", DAV_PATH_PREFIX, SHARE_MOUNT, token),
+                    parts.next(),
+                    Some((id, name)),
+                )
+            }
+            _ => return Err(MountError::NotFound),
+        };
+
+        // Mounts are always collections, so their URLs end with a slash.
+        match rest {
+            Some(rest) => Ok(Mount {
+                prefix,
+                state,
+                rel_path: format!("/{}", rest),
+                share,
+            }),
+            None => Err(MountError::Redirect(prefix)),
+        }
+    }
+
+    /// Respond with the properties of the path (and its children, based on depth).
+    async fn propfind<State>(&self, req: &Request<State>, mount: &Mount) -> Response {
+        let depth = req
+            .header("Depth")
+            .map(|d| d.as_str())
+            .unwrap_or("infinity");
+        if depth != "0" && depth != "1" {
+            // We don't allow infinite depth (RFC 4918, section 9.1)
+            return Response::new(StatusCode::Forbidden);
+        }
+
+        let path = match mount.state.get_path(&mount.rel_path) {
+            Ok(p) => p,
+            Err(_) => return Response::new(StatusCode::BadRequest),
+        };
+
+        let meta = match fs::metadata(&path).await {
+            Ok(m) => m,
+            Err(e) => return Response::new(staticfile::status_for_error(&e)),
+        };
+
+        // Normalize the href using the resolved path.
+        let rel_path = path
+            .strip_prefix(mount.state.root())
+            .expect("resolved path outside root?");
+        let mut href = mount.prefix.clone() + &util::encode_path(rel_path);
+        if meta.is_dir() && !href.ends_with('/') {
+            href.push('/');
+        }
+
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+        );
+        write_response(&mut xml, &href, &meta);
+
+        if depth == "1" && meta.is_dir() {
+            if let Ok(mut entries) = fs::read_dir(&path).await {
+                while let Some(Ok(entry)) = entries.next().await {
+                    let meta = match entry.metadata().await {
+                        Ok(m) => m,
+                        Err(_) => continue,
+                    };
+
+                    let mut child = href.clone() + &util::encode_path(entry.file_name());
+                    if meta.is_dir() {
+                        child.push('/');
+                    }
+
+                    write_response(&mut xml, &child, &meta);
+                }
+            }
+        }
+
+        xml.push_str("</D:multistatus>\n");
+        let mut resp = Response::new(StatusCode::MultiStatus);
+        resp.insert_header(CONTENT_TYPE, "application/xml; charset=utf-8");
+        resp.set_body(Body::from_string(xml));
+        resp
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for WebDav {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let mount = match self.mount(&req) {
+            Ok(m) => m,
+            Err(e) => return Ok(e.into()),
+        };
+
+        let mut resp = match req.method() {
+            Method::Options => Response::new(StatusCode::Ok),
+            Method::PropFind => self.propfind(&req, &mount).await,
+            Method::Get | Method::Head => {
+                if let Some((id, name)) = &mount.share {
+                    let _ = self
+                        .sender
+                        .send((*id, name.clone(), proxy::client_ip(&req)));
+                }
+
+                Responder::new(&req, &mount.state, &mount.rel_path)
+                    .stream()
+                    .await
+            }
+            _ => Response::new(StatusCode::MethodNotAllowed),
+        };
+
+        resp.insert_header("DAV", "1");
+        resp.insert_header(ALLOW, ALLOWED_METHODS);
+        resp.insert_header("MS-Author-Via", "DAV");
+        Ok(resp)
+    }
+}
+
+/// Write the `D:response` element for a resource.
+fn write_response(xml: &mut String, href: &str, meta: &Metadata) {
+    let name = href
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .map(|n| {
+            percent_encoding::percent_decode_str(n)
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .unwrap_or_default();
+
+    let _ = write!(
+        xml,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>",
+        util::escape_xml(href),
+        util::escape_xml(&name)
+    );
+
+    if meta.is_dir() {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let mime = mime_guess::from_path(&name).first_or_octet_stream();
+        let _ = write!(
+            xml,
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+             <D:getcontenttype>{}</D:getcontenttype>",
+            meta.len(),
+            util::escape_xml(mime.as_ref())
+        );
+
+        if let Ok(etag) = staticfile::etag(meta) {
+            let _ = write!(xml, "<D:getetag>\"{}\"</D:getetag>", etag);
+        }
+    }
+
+    if let Ok(modified) = meta.modified() {
+        let _ = write!(
+            xml,
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            httpdate::fmt_http_date(modified)
+        );
+    }
+
+    xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+}